@@ -2,42 +2,120 @@ extern crate hash_hasher;
 extern crate num_cpus;
 extern crate ripline;
 
-use crossbeam_channel::unbounded;
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, Anchored, Input, MatchKind, StartKind};
+use crossbeam_channel::{bounded, unbounded};
 // Special hasher for already hashed data - NTLM is a hash
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::builder::RangedU64ValueParser;
+use clap::{CommandFactory, Parser};
 use memmap2::Mmap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fs::File;
-use std::io::{stdout, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::io::{stdout, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Instant;
-use regex::bytes::Regex;
+use std::time::{Duration, Instant};
+use memchr::{memchr, memchr_iter, memmem, memrchr, memrchr_iter};
+use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use regex_syntax::hir::{Hir, HirKind};
+use regex_syntax::ParserBuilder;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     // Command line arguments /*{{{*/
 
-    /// String of what to search for
-    tofind: String,
+    /// String of what to search for (omit when using --patterns-file)
+    tofind: Option<String>,
 
-    /// File in which to search for matches
-    wordlist: PathBuf,
+    /// Files or directories in which to search for matches, or - for stdin
+    wordlist: Vec<PathBuf>,
+
+    /// File listing more wordlists to search, separated by newlines or NULs, or - for stdin
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
+
+    /// File of newline separated strings to search for, all matched in one pass. With
+    /// --regex each line is a regex, optionally given a name as name<TAB>regex
+    #[arg(long)]
+    patterns_file: Option<PathBuf>,
+
+    /// A regex to search for, give it more than once to search for them all in one pass
+    #[arg(long, value_name = "PATTERN")]
+    regexp: Vec<String>,
+
+    /// File of newline separated lines, print wordlist lines exactly equal to one of them
+    #[arg(long, conflicts_with_all = ["regex", "regexp", "patterns_file"])]
+    intersect: Option<PathBuf>,
+
+    /// Write the values that were never found to this file, or - for stdout
+    #[arg(long, conflicts_with_all = ["regex", "regexp"])]
+    left: Option<PathBuf>,
 
     /// Match a full-line exactly
     #[arg(short, long)]
     exact: bool,
 
     /// Find only the first match
-    #[arg(short, long)]
+    #[arg(short, long, conflicts_with = "max_count")]
     first: bool,
 
+    /// Find only the first NUM matches in the file
+    #[arg(short, long, value_name = "NUM")]
+    max_count: Option<usize>,
+
+    /// Stop once every value to find has been found at least once
+    #[arg(long, conflicts_with_all = ["regex", "regexp"])]
+    first_each: bool,
+
+    /// Print only the matched parts of a line, --position gives where each is
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["invert_match", "after_context", "before_context", "context"]
+    )]
+    only_matching: bool,
+
+    /// Print each regex match filled into a template of capture groups, like '$user@$domain'
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with_all = ["only_matching", "invert_match", "after_context", "before_context", "context"]
+    )]
+    format: Option<String>,
+
+    /// Select the lines that don't match
+    #[arg(long)]
+    invert_match: bool,
+
+    /// Ignore case when matching, only ASCII unless --unicode is given
+    #[arg(short, long)]
+    ignore_case: bool,
+
+    /// Fold Unicode case rather than only ASCII when ignoring case
+    #[arg(long, requires = "ignore_case")]
+    unicode: bool,
+
     /// Search for a regex string, not fixed bytes
     #[arg(short, long)]
     regex: bool,
 
+    /// Print only a count of matching lines
+    #[arg(long)]
+    count: bool,
+
+    /// Write matches in the order they appear in the file
+    #[arg(long)]
+    ordered: bool,
+
     /// Display verbose output
     #[arg(short, long)]
     verbose: bool,
@@ -46,17 +124,136 @@ struct Args {
     #[arg(short, long)]
     position: bool,
 
+    /// Print NUM lines of context after each match
+    #[arg(short = 'A', long, value_name = "NUM")]
+    after_context: Option<usize>,
+
+    /// Print NUM lines of context before each match
+    #[arg(short = 'B', long, value_name = "NUM")]
+    before_context: Option<usize>,
+
+    /// Print NUM lines of context before and after each match
+    #[arg(short = 'C', long, value_name = "NUM")]
+    context: Option<usize>,
+
+    /// Print the line number at which the match was made
+    #[arg(short = 'n', long)]
+    line_number: bool,
+
     /// File read block size
-    #[arg(short, long, default_value_t = 8_388_608)]
+    #[arg(short, long, default_value_t = 8_388_608, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
     block: usize,
 
-    /// Cache block size
-    #[arg(short, long, default_value_t = 2_147_483_648)]
+    /// Cache block size, at least 2 bytes as it's read in halves
+    #[arg(short, long, default_value_t = 2_147_483_648, value_parser = RangedU64ValueParser::<usize>::new().range(2..))]
     cache: usize,
 
     /// Thread shard size
-    #[arg(short, long, default_value_t = 393_728)]
+    #[arg(short, long, default_value_t = 393_728, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
     shard: usize,
+
+    /// Number of threads to search with, defaults to the number of cores, or of cores given to --pin
+    #[arg(short, long, value_name = "NUM", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    threads: Option<usize>,
+
+    /// Pin threads to these cores, like 0-3,6. Searching threads take them in turn, the reader the one after
+    #[arg(long, value_name = "CORES")]
+    pin: Option<String>,
+
+    /// Pick the block, cache and shard sizes from the memory available, and adjust the shard size as we go
+    #[arg(long, conflicts_with_all = ["block", "cache", "shard"])]
+    auto_tune: bool,
+
+    /// Check the threads found the same lines as a slow search of each wordlist a line at a time
+    #[arg(long, conflicts_with_all = ["first", "max_count", "first_each"])]
+    verify: bool,
+}
+/*}}}*/
+
+#[derive(Debug)]
+enum SingrepError {
+    // Everything that can stop us, with what we were doing when it did /*{{{*/
+    // reading a file, or writing one we were asked to
+    Io(PathBuf, std::io::Error),
+    // writing our output
+    Output(std::io::Error),
+    Regex(regex::Error),
+    // values the Aho-Corasick automaton can't be built from
+    Matcher(aho_corasick::BuildError),
+    // a pattern, template or option we can't use
+    Invalid(String),
+    // the kernel refusing one of the calls we manage the cache with
+    Cache(&'static str, std::io::Error),
+    // a thread dying, or hanging up on the others
+    Thread(&'static str),
+    // the threads and --verify's sequential search disagreeing, as (found, checked, checksum)
+    Mismatch((usize, usize, u64), (usize, usize, u64)),
+}
+/*}}}*/
+
+impl std::fmt::Display for SingrepError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SingrepError::Io(path, e) if path.as_os_str() == "-" => write!(f, "(standard input): {e}"),
+            SingrepError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            SingrepError::Output(e) => write!(f, "can't write output: {e}"),
+            SingrepError::Regex(e) => write!(f, "{e}"),
+            SingrepError::Matcher(e) => write!(f, "can't search for these patterns: {e}"),
+            SingrepError::Invalid(message) => write!(f, "{message}"),
+            SingrepError::Cache(call, e) => write!(f, "{call} failed: {e}"),
+            SingrepError::Thread(message) => write!(f, "{message}"),
+            SingrepError::Mismatch(threads, sequential) if threads.0 == sequential.0 && threads.1 == sequential.1 => write!(
+                f,
+                "--verify failed, the threads found {} lines and checked {} but not the same ones as a sequential search",
+                threads.0, threads.1
+            ),
+            SingrepError::Mismatch(threads, sequential) => write!(
+                f,
+                "--verify failed, the threads found {} lines and checked {} but a sequential search found {} and checked {}",
+                threads.0, threads.1, sequential.0, sequential.1
+            ),
+        }
+    }
+}
+
+impl Error for SingrepError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SingrepError::Io(_, e) | SingrepError::Output(e) | SingrepError::Cache(_, e) => Some(e),
+            SingrepError::Regex(e) => Some(e),
+            SingrepError::Matcher(e) => Some(e),
+            SingrepError::Invalid(_) | SingrepError::Thread(_) | SingrepError::Mismatch(..) => None,
+        }
+    }
+}
+
+impl From<regex::Error> for SingrepError {
+    fn from(e: regex::Error) -> Self {
+        SingrepError::Regex(e)
+    }
+}
+
+impl From<aho_corasick::BuildError> for SingrepError {
+    fn from(e: aho_corasick::BuildError) -> Self {
+        SingrepError::Matcher(e)
+    }
+}
+
+impl<T> From<crossbeam_channel::SendError<T>> for SingrepError {
+    fn from(_: crossbeam_channel::SendError<T>) -> Self {
+        SingrepError::Thread("the search threads stopped early")
+    }
+}
+
+impl From<crossbeam_channel::RecvError> for SingrepError {
+    fn from(_: crossbeam_channel::RecvError) -> Self {
+        SingrepError::Thread("the search threads stopped early")
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> SingrepError + '_ {
+    // Tag an I/O error with the file it happened to /*{{{*/
+    move |e| SingrepError::Io(path.to_path_buf(), e)
 }
 /*}}}*/
 
@@ -69,18 +266,24 @@ use libc::{mincore, posix_fadvise, POSIX_FADV_DONTNEED};
 use std::os::unix::io::AsRawFd;
 
 #[cfg(target_os = "macos")]
-fn uncache(file: &Mmap, len: usize) {
+fn uncache(file: &[u8], len: usize) -> Result<(), SingrepError> {
     // Flush a part of the file from disk cache MacOS version/*{{{*/
     let ret = unsafe { msync(file.as_ptr() as _, len, MS_INVALIDATE) };
-    assert!(ret == 0, "msync failed with error {}", ret);
+    if ret != 0 {
+        return Err(SingrepError::Cache("msync", std::io::Error::last_os_error()));
+    }
+    Ok(())
 }
 /*}}}*/
 
 #[cfg(target_os = "linux")]
-fn uncache(file: &File, mmap: &mut Mmap, len: usize) {
+fn uncache(file: &File, mmap: &mut Arc<Data>, len: usize) -> Result<(), SingrepError> {
     // Flush a part of the file from disk cache Linux version/*{{{*/
     let ret = unsafe { posix_fadvise(file.as_raw_fd() as _, 0, len as i64, POSIX_FADV_DONTNEED) };
-    assert!(ret == 0, "posix_fadvise failed with error {}", ret);
+    if ret != 0 {
+        // it returns the error rather than setting errno
+        return Err(SingrepError::Cache("posix_fadvise", std::io::Error::from_raw_os_error(ret)));
+    }
 
     // The need for this re-mmap below is confusing, here's what I know so far: A
     // vanilla PoC that opens a file and mmap reads from the mmap and does the
@@ -89,23 +292,33 @@ fn uncache(file: &File, mmap: &mut Mmap, len: usize) {
     // the reading from the mmap. I've no idea why. But if I redo the mmap, it
     // will respect the drop. When I get round to debugging I'll start here
     // https://github.com/torvalds/linux/blob/786b71f5b754273ccef6d9462e52062b3e1f9877/mm/fadvise.c#L119
-    *mmap = unsafe { Mmap::map(file).unwrap() };
+    // Threads still holding the old map keep it alive until they're done with it
+    let remapped = unsafe { Mmap::map(file) }.map_err(|e| SingrepError::Cache("mmap", e))?;
+    *mmap = Arc::new(Data::Map(remapped));
+    Ok(())
 }
 /*}}}*/
 
-fn mincore_check(file: &Mmap, len: usize, answer: &mut [u8]) {
+fn mincore_check(file: &[u8], len: usize, answer: &mut [u8]) -> Result<(), SingrepError> {
     // Check what part of the file is in disk cache /*{{{*/
     #[cfg(target_os = "linux")]
     let ret = unsafe { mincore(file.as_ptr() as _, len, answer.as_mut_ptr().cast::<u8>()) };
     #[cfg(target_os = "macos")]
     let ret = unsafe { mincore(file.as_ptr() as _, len, answer.as_mut_ptr().cast::<i8>()) };
 
-    assert!(ret == 0, "mincore failed with error {}", ret);
+    if ret != 0 {
+        return Err(SingrepError::Cache("mincore", std::io::Error::last_os_error()));
+    }
+    Ok(())
 }
 /*}}}*/
 
 fn gen_stats(answer: &[u8], pages: usize) -> f64 {
     // Calculate and print disk cache stats /*{{{*/
+    // an empty file has nothing to cache, so is as cached as it'll get
+    if pages == 0 {
+        return 100_f64;
+    }
     let in_cache: usize = answer.iter().map(|x| (x & 0x1) as usize).sum();
     assert!(in_cache <= f64::MAX as usize); // ensure safe usize -> f64 conversion
     assert!(pages <= f64::MAX as usize); // ensure safe usize -> f64 conversion
@@ -115,54 +328,481 @@ fn gen_stats(answer: &[u8], pages: usize) -> f64 {
 }
 /*}}}*/
 
-fn cache_file(file: &mut File, length: usize, block_size: usize, offset: u64) -> f64 {
+#[allow(clippy::unused_io_amount)]
+fn cache_file(file: &mut File, length: usize, block_size: usize, offset: u64) -> Result<f64, SingrepError> {
     // Cache part of the file to disk cache using read() on the file (not mmap) /*{{{*/
     let mut junk = vec![0u8; block_size];
-    file.seek(SeekFrom::Start(offset)).unwrap();
+    let failed = |e| SingrepError::Cache("reading ahead", e);
+    file.seek(SeekFrom::Start(offset)).map_err(failed)?;
     //let start = Instant::now();
     for _ in 0..=(length / block_size) {
         // the read is not handled because we're only doing it to encourage the
         // kernel to cache the file. Ignore clippy's error.
-        file.read(&mut junk).unwrap();
+        file.read(&mut junk).map_err(failed)?;
     }
-    0.0 // return this if we aren't timing, otherwise uncomment below
+    Ok(0.0) // return this if we aren't timing, otherwise uncomment below
         /*
           let elapsed = (start.elapsed().as_secs() as f64)
                         + (f64::from(start.elapsed().subsec_nanos()) / 1_000_000_000.0);
           println!("[+] Read {length} bytes in {elapsed:.2} s ({:.2} GB/s)",
             (length as f64 / elapsed) / 1024.0 / 1024.0 / 1024.0);
-          elapsed
+          Ok(elapsed)
         */
 }
 /*}}}*/
 
+#[derive(Clone, Copy, PartialEq)]
+enum CaseFold {
+    // How to ignore case when matching /*{{{*/
+    Sensitive,
+    Ascii,
+    Unicode,
+}
+/*}}}*/
+
 #[derive(Clone)]
 struct ToFind {
-    // Structuroe to hold our value to find /*{{{*/
-    value: Vec<u8>,
+    // Structure to hold our values to find /*{{{*/
     start: [bool; 256],
     second: [bool; 256],
+    // matches all the values in one pass, pattern ids index into values
+    automaton: AhoCorasick,
+    // the longest non-overlapping matches, for printing only what matched
+    leftmost: Option<AhoCorasick>,
+    values: Arc<Vec<Vec<u8>>>,
+    // millions of exact lines are cheaper to hash than to build an automaton for,
+    // each maps to its position in the input
+    lines: Option<Arc<HashMap<Vec<u8>, usize>>>,
+    // the original of any line that case folding changed
+    unfolded: Arc<HashMap<usize, Vec<u8>>>,
+    fold: CaseFold,
+    // whether lines need folding before they're compared
+    fold_lines: bool,
 }
 /*}}}*/
 
-fn parse_tofind(tofind: &str) -> Result<ToFind, Box<dyn Error>> {
-    // Turn input hashes into required data structures /*{{{*/
-    let value: Vec<u8> = tofind.try_into()?;
+fn read_needles(path: &PathBuf) -> Result<Vec<Vec<u8>>, SingrepError> {
+    // Read a file of values to find, split on newlines like the wordlist shards /*{{{*/
+    let contents = std::fs::read(path).map_err(io_error(path))?;
+    // drop duplicates so every value has a single id to be found under
+    let mut seen = HashSet::new();
+    let needles: Vec<Vec<u8>> = contents
+        .split(|c| *c == 10_u8)
+        .filter(|l| !l.is_empty() && seen.insert(*l))
+        .map(|l| l.to_vec())
+        .collect();
+    if needles.is_empty() {
+        return Err(SingrepError::Invalid(format!("no patterns found in {}", path.display())));
+    }
+    Ok(needles)
+}
+/*}}}*/
+
+fn read_files_from(path: &PathBuf) -> Result<Vec<PathBuf>, SingrepError> {
+    // Read a list of wordlists, separated by NULs if there are any, otherwise newlines /*{{{*/
+    let mut list = Vec::new();
+    match path.as_os_str() == "-" {
+        true => std::io::stdin().read_to_end(&mut list),
+        false => File::open(path).and_then(|mut file| file.read_to_end(&mut list)),
+    }
+    .map_err(io_error(path))?;
+    let sep = match memchr(0, &list) {
+        Some(_) => 0,
+        None => 10_u8,
+    };
+    Ok(list
+        .split(|c| *c == sep)
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(OsStr::from_bytes(name)))
+        .collect())
+}
+/*}}}*/
+
+fn find_files(path: PathBuf, files: &mut Vec<PathBuf>, errors: &mut Vec<SingrepError>) {
+    // Add a wordlist to search, or every file under it if it's a directory, carrying on past any we can't read /*{{{*/
+    if path.as_os_str() == "-" || !path.is_dir() {
+        files.push(path);
+        return;
+    }
+    let mut entries = match std::fs::read_dir(&path).and_then(|dir| dir.collect::<Result<Vec<_>, _>>()) {
+        Ok(entries) => entries,
+        Err(e) => return errors.push(SingrepError::Io(path, e)),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        // like grep -r, symlinks are only followed when they're given to us
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => find_files(entry.path(), files, errors),
+            Ok(kind) if kind.is_file() => files.push(entry.path()),
+            Ok(_) => {}
+            Err(e) => errors.push(SingrepError::Io(entry.path(), e)),
+        }
+    }
+}
+/*}}}*/
+
+// a regex and the name to tag its matches with, if it was given one
+type NamedRegex = (Option<String>, String);
+
+struct Regexes {
+    // Structure to hold the compiled regexes /*{{{*/
+    list: Vec<Regex>,
+    set: Option<RegexSet>,
+    names: Vec<Vec<u8>>,
+    prefilter: Option<Prefilter>,
+}
+/*}}}*/
+
+#[derive(Clone)]
+enum Prefilter {
+    // Literals that any match has to contain, searched for across a whole shard /*{{{*/
+    One(Box<memmem::Finder<'static>>),
+    Many(AhoCorasick),
+}
+/*}}}*/
+
+fn required_literals(hir: &Hir) -> Option<Vec<Vec<u8>>> {
+    // Work out a set of literals, one of which has to be in anything the regex matches /*{{{*/
+    let literals = match hir.kind() {
+        HirKind::Literal(literal) => vec![literal.0.to_vec()],
+        HirKind::Capture(capture) => required_literals(&capture.sub)?,
+        HirKind::Repetition(repetition) if repetition.min > 0 => required_literals(&repetition.sub)?,
+        // any one of the parts will do, so pick the one with the longest literals
+        HirKind::Concat(parts) => parts
+            .iter()
+            .filter_map(required_literals)
+            .max_by_key(|literals| {
+                let shortest = literals.iter().map(Vec::len).min().unwrap_or(0);
+                (shortest, std::cmp::Reverse(literals.len()))
+            })?,
+        // every branch needs a literal of its own
+        HirKind::Alternation(branches) => {
+            let mut literals = Vec::new();
+            for branch in branches {
+                literals.extend(required_literals(branch)?);
+            }
+            literals
+        }
+        _ => return None,
+    };
+    // a literal spanning a newline can't be in a line, and too many aren't worth it
+    if literals.len() > 256 || literals.iter().any(|l| l.is_empty() || l.contains(&10_u8)) {
+        return None;
+    }
+    Some(literals)
+}
+/*}}}*/
+
+fn build_prefilter(patterns: &[NamedRegex], fold: CaseFold) -> Option<Prefilter> {
+    // Build a prefilter from the literals all our regexes need /*{{{*/
+    // Unicode folded literals could match in ways a byte search wouldn't find
+    if patterns.is_empty() || fold == CaseFold::Unicode {
+        return None;
+    }
+    let mut literals = Vec::new();
+    for (_, pattern) in patterns {
+        // parsed as the regexes were, without Unicode \xFF is a byte rather than ÿ
+        let hir = ParserBuilder::new().utf8(false).unicode(fold != CaseFold::Ascii).build().parse(pattern).ok()?;
+        literals.extend(required_literals(&hir)?);
+    }
+    literals.sort_unstable();
+    literals.dedup();
+    match (literals.len(), fold) {
+        (1, CaseFold::Sensitive) => Some(Prefilter::One(Box::new(memmem::Finder::new(&literals[0]).into_owned()))),
+        _ => AhoCorasick::builder()
+            .ascii_case_insensitive(fold == CaseFold::Ascii)
+            .build(&literals)
+            .ok()
+            .map(Prefilter::Many),
+    }
+}
+/*}}}*/
+
+fn literal_prefilter(tofind: &ToFind) -> Option<Prefilter> {
+    // Search whole shards for our values, rather than each line on its own /*{{{*/
+    // a hash set of lines has nothing to search for, and folded values need folded lines
+    if tofind.lines.is_some() || tofind.fold == CaseFold::Unicode {
+        return None;
+    }
+    let values = &tofind.values;
+    if values.iter().any(|v| v.is_empty() || v.contains(&10_u8)) {
+        return None;
+    }
+    match (values.len(), tofind.fold) {
+        (1, CaseFold::Sensitive) => Some(Prefilter::One(Box::new(memmem::Finder::new(&values[0]).into_owned()))),
+        _ => AhoCorasick::builder()
+            .ascii_case_insensitive(tofind.fold == CaseFold::Ascii)
+            .build(values.iter())
+            .ok()
+            .map(Prefilter::Many),
+    }
+}
+/*}}}*/
+
+fn next_candidate(prefilter: &Prefilter, shard: &[u8], from: usize) -> Option<usize> {
+    // Find where in the shard the next line worth checking is /*{{{*/
+    match prefilter {
+        Prefilter::One(finder) => finder.find(&shard[from..]).map(|at| from + at),
+        Prefilter::Many(automaton) => automaton
+            .find(Input::new(shard).span(from..shard.len()))
+            .map(|m| m.start()),
+    }
+}
+/*}}}*/
+
+fn count_skipped(skipped: &[u8]) -> (usize, usize) {
+    // Count the lines, and how many of them are empty, in a run of whole lines /*{{{*/
+    // The last run of a file may end in a line with no trailing newline
+    let (mut lines, mut empty) = (0, 0);
+    let mut line_start = 0;
+    for nl in memchr_iter(10_u8, skipped) {
+        lines += 1;
+        if nl == line_start {
+            empty += 1;
+        }
+        line_start = nl + 1;
+    }
+    if line_start < skipped.len() {
+        lines += 1;
+    }
+    (lines, empty)
+}
+/*}}}*/
+
+fn read_regexes(path: &PathBuf) -> Result<Vec<NamedRegex>, SingrepError> {
+    // Read a file of regexes, one per line, each optionally named as name<TAB>regex /*{{{*/
+    let contents = std::fs::read_to_string(path).map_err(io_error(path))?;
+    let regexes: Vec<NamedRegex> = contents
+        .split('\n')
+        .filter(|l| !l.is_empty())
+        .map(|l| match l.split_once('\t') {
+            Some((name, regex)) => (Some(name.to_string()), regex.to_string()),
+            None => (None, l.to_string()),
+        })
+        .collect();
+    if regexes.is_empty() {
+        return Err(SingrepError::Invalid(format!("no patterns found in {}", path.display())));
+    }
+    Ok(regexes)
+}
+/*}}}*/
+
+fn parse_regexes(
+    patterns: &[NamedRegex],
+    fold: CaseFold,
+) -> Result<Regexes, SingrepError> {
+    // Compile the regexes, and a set to match them all at once if there's more than one /*{{{*/
+    // ASCII case folding means leaving Unicode mode altogether
+    let (ignore_case, unicode) = (fold != CaseFold::Sensitive, fold != CaseFold::Ascii);
+    let mut list = Vec::with_capacity(patterns.len());
+    for (_, pattern) in patterns {
+        list.push(RegexBuilder::new(pattern).case_insensitive(ignore_case).unicode(unicode).build()?);
+    }
+    let set = match patterns.len() > 1 {
+        true => Some(
+            RegexSetBuilder::new(patterns.iter().map(|(_, pattern)| pattern))
+                .case_insensitive(ignore_case)
+                .unicode(unicode)
+                .build()?,
+        ),
+        false => None,
+    };
+    // unnamed regexes go by their position, counting from 1
+    let names = patterns
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| name.clone().unwrap_or_else(|| (i + 1).to_string()).into_bytes())
+        .collect();
+    let prefilter = build_prefilter(patterns, fold);
+    Ok(Regexes {
+        list,
+        set,
+        names,
+        prefilter,
+    })
+}
+/*}}}*/
+
+fn fold_case(value: &[u8], fold: CaseFold, into: &mut Vec<u8>) {
+    // Fold a value or line to lower case, so we can compare it with folded values /*{{{*/
+    into.clear();
+    match (fold, std::str::from_utf8(value)) {
+        (CaseFold::Unicode, Ok(text)) => {
+            let mut utf8 = [0u8; 4];
+            for c in text.chars() {
+                if c.is_ascii() {
+                    into.push(c.to_ascii_lowercase() as u8);
+                    continue;
+                }
+                for lower in c.to_lowercase() {
+                    // a final sigma is still a sigma
+                    let lower = if lower == 'ς' { 'σ' } else { lower };
+                    into.extend_from_slice(lower.encode_utf8(&mut utf8).as_bytes());
+                }
+            }
+        }
+        // invalid UTF-8 can still have its ASCII folded
+        (CaseFold::Unicode | CaseFold::Ascii, _) => {
+            into.extend(value.iter().map(|c| c.to_ascii_lowercase()));
+        }
+        (CaseFold::Sensitive, _) => into.extend_from_slice(value),
+    }
+}
+/*}}}*/
+
+fn unfold_span(clear: &[u8], start: usize, end: usize) -> (usize, usize) {
+    // Map a match in a Unicode folded line back onto the line before it was folded /*{{{*/
+    let Ok(text) = std::str::from_utf8(clear) else {
+        // only ASCII was folded, which doesn't change any lengths
+        return (start, end);
+    };
+    let (mut from, mut to) = (0, clear.len());
+    let mut folded_pos = 0;
+    for (i, c) in text.char_indices() {
+        if folded_pos <= start {
+            from = i;
+        }
+        if folded_pos >= end {
+            to = i;
+            break;
+        }
+        folded_pos += match c.is_ascii() {
+            true => 1,
+            false => c.to_lowercase().map(char::len_utf8).sum(),
+        };
+    }
+    (from, to)
+}
+/*}}}*/
+
+fn mark_start(start: &mut [bool; 256], second: &mut [bool; 256], key: &[u8]) {
+    // Store the first and second byte of a value, so for small lists of them /*{{{*/
+    // we can do a cheaper check than a full automaton search
+    // an empty value only matches empty lines exactly, which have no bytes to check
+    let Some(first) = key.first() else {
+        return;
+    };
+    // an ASCII folding automaton sees lines in whatever case they come
+    for c in [*first, first.to_ascii_uppercase()] {
+        start[c as usize] = true;
+    }
+    if key.len() > 1 {
+        for c in [key[1], key[1].to_ascii_uppercase()] {
+            second[c as usize] = true;
+        }
+    }
+}
+/*}}}*/
+
+fn parse_lines(path: &PathBuf, fold: CaseFold) -> Result<ToFind, SingrepError> {
+    // Read a file of millions of lines straight into a hash set of them, split like the wordlist shards /*{{{*/
+    let mut input = BufReader::with_capacity(1_048_576, File::open(path).map_err(io_error(path))?);
+    let mut start = [false; 256];
+    let mut second = [false; 256];
+    // values that fold to the same key are found together, under the first one
+    let mut lines: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut unfolded: HashMap<usize, Vec<u8>> = HashMap::new();
+    let mut line = Vec::new();
+    let mut key = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(10, &mut line).map_err(io_error(path))? == 0 {
+            break;
+        }
+        let value = line.strip_suffix(b"\n").unwrap_or(&line);
+        if value.is_empty() {
+            continue;
+        }
+        fold_case(value, fold, &mut key);
+        if lines.contains_key(&key) {
+            continue;
+        }
+        mark_start(&mut start, &mut second, &key);
+        let id = lines.len();
+        if key != value {
+            unfolded.insert(id, value.to_vec());
+        }
+        lines.insert(std::mem::take(&mut key), id);
+    }
+    if lines.is_empty() {
+        return Err(SingrepError::Invalid(format!("no lines found in {}", path.display())));
+    }
+    Ok(ToFind {
+        start,
+        second,
+        automaton: AhoCorasick::new(Vec::<Vec<u8>>::new())?,
+        leftmost: None,
+        values: Arc::new(Vec::new()),
+        lines: Some(Arc::new(lines)),
+        unfolded: Arc::new(unfolded),
+        fold,
+        // lines are looked up as they are, so need folding by us whatever the fold
+        fold_lines: fold != CaseFold::Sensitive,
+    })
+}
+/*}}}*/
 
-    // store the first and last byte of input hashes, so for small input hash lists
-    // we can do a cheaper check than a hashmap lookup
+fn parse_tofind(
+    values: Vec<Vec<u8>>,
+    exact: bool,
+    fold: CaseFold,
+    only_matching: bool,
+) -> Result<ToFind, SingrepError> {
+    // Turn input hashes into required data structures /*{{{*/
     let mut start = [false; 256];
     let mut second = [false; 256];
 
-    start[value[0] as usize] = true;
-    if value.len() > 1 {
-        second[value[1] as usize] = true;
+    // The automaton folds ASCII by itself, everything else sees folded lines
+    let fold_lines = fold == CaseFold::Unicode;
+    let mut key = Vec::new();
+
+    let mut seen = HashSet::new();
+    let mut keys = Vec::new();
+    let mut kept = Vec::new();
+    for value in values {
+        fold_case(&value, fold, &mut key);
+        if seen.insert(key.clone()) {
+            mark_start(&mut start, &mut second, &key);
+            keys.push(key.clone());
+            kept.push(value);
+        }
     }
 
+    // Leftmost-longest lets an anchored search return the longest value that
+    // prefixes a line, which is what --exact needs to compare against. Substring
+    // searches use standard semantics so overlapping values can all be found.
+    let automaton = match exact {
+        true => AhoCorasickBuilder::new()
+            .match_kind(MatchKind::LeftmostLongest)
+            .start_kind(StartKind::Anchored)
+            .ascii_case_insensitive(fold == CaseFold::Ascii)
+            .build(&keys)?,
+        false => AhoCorasickBuilder::new()
+            .match_kind(MatchKind::Standard)
+            .start_kind(StartKind::Unanchored)
+            .ascii_case_insensitive(fold == CaseFold::Ascii)
+            .build(&keys)?,
+    };
+    let leftmost = match only_matching && !exact {
+        true => Some(
+            AhoCorasickBuilder::new()
+                .match_kind(MatchKind::LeftmostLongest)
+                .ascii_case_insensitive(fold == CaseFold::Ascii)
+                .build(&keys)?,
+        ),
+        false => None,
+    };
+
     Ok(ToFind {
-        value,
         start,
         second,
+        automaton,
+        leftmost,
+        values: Arc::new(kept),
+        lines: None,
+        unfolded: Arc::new(HashMap::new()),
+        fold,
+        fold_lines,
     })
 }
 /*}}}*/
@@ -170,9 +810,10 @@ fn parse_tofind(tofind: &str) -> Result<ToFind, Box<dyn Error>> {
 struct Wordlist {
     // Structure to hold our wordlist stats /*{{{*/
     file: File,
-    mmap: Mmap,
+    mmap: Arc<Data>,
     cache_point: usize,
     length: usize,
+    #[allow(dead_code)] // only used by the debugging stats in read_wordlist
     pages: usize,
     cache_size: usize,
 }
@@ -183,52 +824,56 @@ fn initialise_wordlist(
     cache_size: usize,
     block_size: usize,
     verbose: bool,
-) -> Result<Wordlist, Box<dyn Error>> {
+    stop: &AtomicBool,
+) -> Result<Wordlist, SingrepError> {
     // Read and cache the start of the wordlist /*{{{*/
-    let mut wordlist_file = File::open(&path)?;
-    let wordlist_mmap = unsafe { Mmap::map(&wordlist_file)? };
+    let mut wordlist_file = File::open(path).map_err(io_error(path))?;
+    let wordlist_mmap = unsafe { Mmap::map(&wordlist_file) }.map_err(io_error(path))?;
 
     let page_size = page_size::get();
     let wordlist_length = wordlist_mmap.len();
-    let wordlist_pages = (wordlist_length + page_size - 1) / page_size;
+    let wordlist_pages = wordlist_length.div_ceil(page_size);
     let cache_point;
 
     let mut answer = vec![0u8; wordlist_pages];
-    mincore_check(&wordlist_mmap, wordlist_length, &mut answer);
+    mincore_check(&wordlist_mmap, wordlist_length, &mut answer)?;
     let mut percent_cached: f64 = gen_stats(&answer, wordlist_pages);
     if verbose {
-        println!("[+] Wordlist is {wordlist_length} bytes and {wordlist_pages} pages, currently {percent_cached:.2}% cached");
+        write_verbose(
+            format_args!("[+] Wordlist is {wordlist_length} bytes and {wordlist_pages} pages, currently {percent_cached:.2}% cached"),
+            stop,
+        )?;
     }
 
     if percent_cached < 97.0 {
         if wordlist_length > cache_size {
-            let _elapsed_time = cache_file(&mut wordlist_file, cache_size, block_size, 0);
-            mincore_check(&wordlist_mmap, wordlist_length, &mut answer);
+            let _elapsed_time = cache_file(&mut wordlist_file, cache_size, block_size, 0)?;
+            mincore_check(&wordlist_mmap, wordlist_length, &mut answer)?;
             percent_cached = gen_stats(&answer, wordlist_pages);
             assert!(wordlist_length <= f64::MAX as usize); // safe f64 conversion
             if verbose && percent_cached >= (wordlist_length / cache_size) as f64 {
-                println!("[*] Successfully cached first part of wordlist");
+                write_verbose(format_args!("[*] Successfully cached first part of wordlist"), stop)?;
             }
             cache_point = cache_size;
         } else {
-            let _elapsed_time = cache_file(&mut wordlist_file, wordlist_length, block_size, 0);
-            mincore_check(&wordlist_mmap, wordlist_length, &mut answer);
+            let _elapsed_time = cache_file(&mut wordlist_file, wordlist_length, block_size, 0)?;
+            mincore_check(&wordlist_mmap, wordlist_length, &mut answer)?;
             percent_cached = gen_stats(&answer, wordlist_pages);
             if verbose && percent_cached >= 95.0 {
-                println!("Successfully cached wordlist");
+                write_verbose(format_args!("Successfully cached wordlist"), stop)?;
             }
             cache_point = wordlist_length;
         }
     } else {
         if verbose {
-            println!("Wordlist already cached");
+            write_verbose(format_args!("Wordlist already cached"), stop)?;
         }
         cache_point = wordlist_length;
     }
 
     Ok(Wordlist {
         file: wordlist_file,
-        mmap: wordlist_mmap,
+        mmap: Arc::new(Data::Map(wordlist_mmap)),
         cache_point,
         length: wordlist_length,
         pages: wordlist_pages,
@@ -237,195 +882,1674 @@ fn initialise_wordlist(
 }
 /*}}}*/
 
-struct Workers {
-    // Structure to hold our thread worker info /*{{{*/
-    threadnum: usize,
-    threadhand: Vec<JoinHandle<()>>,
-    tx: crossbeam_channel::Sender<Option<(usize, Vec<u8>)>>,
-    //rx: crossbeam_channel::Receiver<Option<Vec<u8>>>,
-    //tx2: crossbeam_channel::Sender<Stats>,
-    rx2: crossbeam_channel::Receiver<Stats>,
-}
-/*}}}*/
-
-#[derive(Clone, Copy)] // needed to send via channels between thread and main
-struct Stats {
-    // Structure to hold counters from the threads /*{{{*/
-    cracked: usize,
-    hashed: usize,
-    waits: usize,
-    kbs: usize,
+enum Data {
+    // Where the threads read the wordlist from /*{{{*/
+    // the whole file, mapped into memory
+    Map(Mmap),
+    // a piece of a stream, handed back to the reader to refill once nobody needs it
+    Buffer(Vec<u8>, crossbeam_channel::Sender<Vec<u8>>),
 }
 /*}}}*/
 
-fn find(tofind: &ToFind, clear: &[u8]) -> bool {
-    // Matching routine /*{{{*/
+impl std::ops::Deref for Data {
+    type Target = [u8];
 
-    // for small hashlists, can we get away with this cheaper check
-    if !tofind.start[clear[0] as usize]
-    {
-        return false;
-    }
-    if clear.len() > 1 && !tofind.second[clear[1] as usize]
-    {
-        return false;
+    fn deref(&self) -> &[u8] {
+        match self {
+            Data::Map(map) => map,
+            Data::Buffer(buffer, _) => buffer,
+        }
     }
+}
 
-    // check if the generated hash is in our input hash list
-    if tofind.value == clear {
-        return true;
+impl Drop for Data {
+    fn drop(&mut self) {
+        if let Data::Buffer(buffer, free) = self {
+            // the reader may have finished with its buffers already
+            let _ = free.send(std::mem::take(buffer));
+        }
     }
+}
 
-    false
+enum Source {
+    // What we're searching /*{{{*/
+    // a file we can map, and manage the cache of
+    File(Wordlist),
+    // stdin, a pipe or anything else that has to be read as it comes
+    Stream(Box<dyn Read>),
 }
 /*}}}*/
 
-fn setup_workers(tofind: &ToFind, args: &Args) -> Workers {
-    // Fire off our worker threads to wait for the data from the wordlist /*{{{*/
-    let threadnum = num_cpus::get(); // set the number of threads to the number of cores
-    let mut threadhand: Vec<JoinHandle<_>> = Vec::new();
-    let updatethresh = 1; // how often to update the main thread
+#[derive(Clone, Copy, Debug)]
+enum Compression {
+    // What a compressed wordlist was compressed with /*{{{*/
+    Gzip,
+    Zstd,
+    Xz,
+}
+/*}}}*/
 
-    let re = Regex::new(&args.tofind).unwrap();
+fn compression(magic: &[u8]) -> Option<Compression> {
+    // Recognise a compressed wordlist from its first few bytes /*{{{*/
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Some(Compression::Gzip)
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(Compression::Zstd)
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Some(Compression::Xz)
+    } else {
+        None
+    }
+}
+/*}}}*/
 
-    // We clone the reciever multiple times which is how the threads pick up new clears
-    // Can't do that with mpsc which only allows cloning the sender, need crossbeam
-    let (tx, rx): (
-        crossbeam_channel::Sender<Option<(usize, Vec<u8>)>>,
-        crossbeam_channel::Receiver<Option<(usize, Vec<u8>)>>,
-    ) = unbounded();
-    let (tx2, rx2): (
-        crossbeam_channel::Sender<Stats>,
-        crossbeam_channel::Receiver<Stats>,
-    ) = unbounded();
+fn decoder<'a, R: Read + Send + 'a>(kind: Compression, input: R) -> std::io::Result<Box<dyn Read + Send + 'a>> {
+    // Wrap something compressed in a reader that gives back what it decompresses to /*{{{*/
+    // all three carry on through concatenated members, as their command line tools do
+    Ok(match kind {
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(BufReader::new(input))),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(input)?),
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(BufReader::new(input))),
+    })
+}
+/*}}}*/
 
-    for _ in 0..threadnum {
-        //for j in 0..threadnum {
-        // Make copies of these two for the threads
-        let rx_thread = rx.clone();
-        let tx2_thread = tx2.clone();
-        let tofind_thread = tofind.clone();
-        let args_exact = args.exact;
-        let args_position = args.position;
-        let args_regex = args.regex;
-        let re_thread = re.clone();
-        //let to_find_thread = hashes.hashlist.clone();
-        threadhand.push(thread::spawn(move || {
-            // The in-thread worker code /*{{{*/
-            // Pre-allocate to reduce alloc overhead
-            let mut out: Vec<u8> = Vec::with_capacity(8192);
-            let mut stats = Stats {
-                cracked: 0,
-                hashed: 0,
-                waits: 0,
-                kbs: 0, // not used here
+fn bgzf_blocks(data: &[u8]) -> Option<Vec<usize>> {
+    // Find where each block of a bgzf file starts, from the sizes in their headers /*{{{*/
+    // bgzf is gzip cut into members of at most 64K, each with its compressed size
+    // in a BC extra field, so they can be found without inflating anything
+    let mut blocks = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let header = data.get(at..at + 12)?;
+        if header[..4] != [0x1f, 0x8b, 8, 4] {
+            return None;
+        }
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let extra = data.get(at + 12..at + 12 + xlen)?;
+        let mut size = None;
+        let mut field = 0;
+        while field + 4 <= extra.len() {
+            let len = u16::from_le_bytes([extra[field + 2], extra[field + 3]]) as usize;
+            if extra[field..field + 2] == *b"BC" && len == 2 {
+                let bsize = extra.get(field + 4..field + 6)?;
+                size = Some(u16::from_le_bytes([bsize[0], bsize[1]]) as usize + 1);
+            }
+            field += 4 + len;
+        }
+        blocks.push(at);
+        at += size?;
+    }
+    (at == data.len()).then_some(blocks)
+}
+/*}}}*/
+
+fn zstd_frames(data: &[u8]) -> Option<Vec<usize>> {
+    // Find where each frame of a zstd file starts, by walking the block headers /*{{{*/
+    // Skippable frames, like the seek table of the seekable format, stay with the
+    // frame before them, which the decoder is happy to skip over
+    let mut frames = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+        let magic = u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?);
+        if magic & 0xffff_fff0 == 0x184d_2a50 {
+            let size = u32::from_le_bytes(data.get(at + 4..at + 8)?.try_into().ok()?);
+            at += 8 + size as usize;
+            continue;
+        }
+        if magic != 0xfd2f_b528 {
+            return None;
+        }
+        frames.push(at);
+        let descriptor = *data.get(at + 4)?;
+        let single = descriptor & 0x20 != 0;
+        let dictionary = [0, 1, 2, 4][(descriptor & 3) as usize];
+        let content_size = match descriptor >> 6 {
+            0 => single as usize,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        at += 5 + !single as usize + dictionary + content_size;
+        loop {
+            let block = data.get(at..at + 3)?;
+            let block = block[0] as usize | (block[1] as usize) << 8 | (block[2] as usize) << 16;
+            at += 3 + match (block >> 1) & 3 {
+                1 => 1, // run length, stored as the one byte
+                3 => return None,
+                _ => block >> 3,
             };
+            if block & 1 == 1 {
+                break;
+            }
+        }
+        if descriptor & 4 != 0 {
+            at += 4; // checksum
+        }
+    }
+    (at == data.len()).then_some(frames)
+}
+/*}}}*/
 
-            // Fetch clears from the channel
-            loop {
-                //for recv in rx_thread {
-                if let Ok(recv) = rx_thread.try_recv() {
-                    // We wrap the message in an Option to allow for a kill signal
-                    // Our thread recieved None lets dump our buffer and exit
-                    if recv == None {
-                        //println!("Break {}",j);
-                        stdout().write_all(&out).unwrap();
-                        tx2_thread.send(stats).unwrap();
-                        break;
-                    }
-                    // We got some clears to crack
-                    if let Some((mut pos, message)) = recv {
-                        for clear in message.split(|c| *c == 10_u8).filter(|l| !l.is_empty()) {
-                            stats.hashed += 1;
-                            pos += clear.len() + 1;
-                            let mut found = false;
-                            //println!("Thread {} recieved: '{:?}'",j,std::str::from_utf8(clear));
-
-                            // encoding error
-                            if clear.is_empty() {
-                                continue;
-                            }
+fn group_frames(frames: &[usize], length: usize) -> Vec<(usize, usize)> {
+    // Batch frames into pieces big enough to be worth handing to a thread /*{{{*/
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for &frame in frames.iter().skip(1) {
+        let start = groups.last().map_or(0, |&(_, end)| end);
+        if frame - start >= INFLATE_GROUP {
+            groups.push((start, frame));
+        }
+    }
+    groups.push((groups.last().map_or(0, |&(_, end)| end), length));
+    groups
+}
+/*}}}*/
 
-                            if args_regex {
-                                found = re_thread.is_match(clear);
-                            } else { //regexp
-                                if args_exact {
-                                    found = find(&tofind_thread, clear);
-                                    if !found { continue; }
-                                } else {
-                                    // Not exact match
-                                    for sub in clear.windows(tofind_thread.value.len()) {
-                                        found = find(&tofind_thread, sub);
-                                        if found { break; }
-                                    }
-                                }
-                            }
+// How much compressed data a decompression thread takes at once
+const INFLATE_GROUP: usize = 1_048_576;
+// How much decompressed data goes to the reader at once
+const INFLATE_CHUNK: usize = 4_194_304;
 
-                            if found {
-                                stats.cracked += 1;
-                                if args_position {
-                                    write!(&mut out, "{}", pos-clear.len()-1).unwrap();
-                                    // extend_from_slice is faster than push
-                                    out.extend_from_slice(&[58]); // colon
-                                }
-                                out.extend_from_slice(clear); // clear text
-                                out.extend_from_slice(&[10]); // newline
-                                                              // check if our output buffer should be flushed
-                                if out.len() >= 8192 {
-                                    // make sure this comparison aligns with capacity
-                                    stdout().write_all(&out).unwrap();
-                                    out.clear();
+struct Inflate {
+    // Reads the decompressed wordlist back from the decompression thread /*{{{*/
+    rx: crossbeam_channel::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    at: usize,
+}
+/*}}}*/
+
+impl Read for Inflate {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.at == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.at = 0;
+                }
+                // the decompression thread has finished
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.at);
+        buf[..len].copy_from_slice(&self.chunk[self.at..self.at + len]);
+        self.at += len;
+        Ok(len)
+    }
+}
+
+fn inflate_stream(mut input: Box<dyn Read + Send>) -> Inflate {
+    // Decompress on a thread of its own, so the reader only has to cut lines /*{{{*/
+    // it shares the reader's core with --pin, the reader spends most of its time waiting on it
+    let (tx, rx) = bounded(4);
+    thread::spawn(move || loop {
+        let mut chunk = vec![0u8; INFLATE_CHUNK];
+        let mut filled = 0;
+        let result = loop {
+            match input.read(&mut chunk[filled..]) {
+                Ok(0) => break Ok(()),
+                Ok(len) => {
+                    filled += len;
+                    if filled == chunk.len() {
+                        break Ok(());
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        chunk.truncate(filled);
+        let last = filled < INFLATE_CHUNK || result.is_err();
+        let sent = match result {
+            Ok(()) if filled == 0 => break,
+            Ok(()) => tx.send(Ok(chunk)),
+            Err(e) => tx.send(Err(e)),
+        };
+        if sent.is_err() || last {
+            break;
+        }
+    });
+    Inflate { rx, chunk: Vec::new(), at: 0 }
+}
+/*}}}*/
+
+fn inflate_frames(
+    map: Arc<Mmap>,
+    kind: Compression,
+    groups: Vec<(usize, usize)>,
+    threadnum: usize,
+    cores: &[usize],
+) -> Inflate {
+    // Decompress independent frames on several threads, handing them on in order /*{{{*/
+    let (tx, rx) = bounded(4);
+    let cores = cores.to_vec();
+    thread::spawn(move || {
+        let (jobs, todo) = unbounded::<(usize, usize, usize)>();
+        let (done_tx, done) = unbounded();
+        for j in 0..threadnum {
+            let todo = todo.clone();
+            let done_tx = done_tx.clone();
+            let map = Arc::clone(&map);
+            let cores = cores.clone();
+            thread::spawn(move || {
+                // we'd inherit the reader's core, so spread out over the searching threads' instead
+                pin_to(&cores, j);
+                for (group, start, end) in todo.iter() {
+                    let mut out = Vec::new();
+                    let result = decoder(kind, &map[start..end]).and_then(|mut d| d.read_to_end(&mut out));
+                    if done_tx.send((group, result.map(|_| out))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // only the threads can send now, so a recv fails if they all die
+        drop(done_tx);
+
+        // keep a few groups ahead of the reader, holding the ones that finish early
+        let window = threadnum * 2;
+        let mut queued = 0;
+        let mut ready = BTreeMap::new();
+        for next in 0..groups.len() {
+            while queued < groups.len() && queued < next + window {
+                let (start, end) = groups[queued];
+                let _ = jobs.send((queued, start, end));
+                queued += 1;
+            }
+            let chunk = loop {
+                if let Some(chunk) = ready.remove(&next) {
+                    break chunk;
+                }
+                match done.recv() {
+                    Ok((group, result)) => ready.insert(group, result),
+                    Err(_) => return,
+                };
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+    Inflate { rx, chunk: Vec::new(), at: 0 }
+}
+/*}}}*/
+
+fn open_source(
+    path: &PathBuf,
+    cache_size: usize,
+    block_size: usize,
+    threadnum: usize,
+    cores: &[usize],
+    verbose: bool,
+    stop: &AtomicBool,
+) -> Result<Source, SingrepError> {
+    // Work out whether the wordlist can be mapped, or has to be read as a stream /*{{{*/
+    let input: Box<dyn Read + Send> = match path.as_os_str() == "-" {
+        true => Box::new(std::io::stdin()),
+        false => {
+            let mut file = File::open(path).map_err(io_error(path))?;
+            if !file.metadata().map_err(io_error(path))?.is_file() {
+                Box::new(file)
+            } else {
+                let mut magic = [0u8; 6];
+                let len = file.read(&mut magic).map_err(io_error(path))?;
+                let Some(kind) = compression(&magic[..len]) else {
+                    return Ok(Source::File(initialise_wordlist(path, cache_size, block_size, verbose, stop)?));
+                };
+                // a file cut into independent frames can be decompressed in parallel
+                let map = Arc::new(unsafe { Mmap::map(&file) }.map_err(io_error(path))?);
+                let frames = match kind {
+                    Compression::Gzip => bgzf_blocks(&map),
+                    Compression::Zstd => zstd_frames(&map),
+                    Compression::Xz => None,
+                };
+                if let Some(frames) = frames.filter(|frames| frames.len() > 1) {
+                    let groups = group_frames(&frames, map.len());
+                    if verbose {
+                        write_verbose(
+                            format_args!(
+                                "[+] Wordlist is {kind:?} compressed in {} frames, decompressing {} pieces on {threadnum} threads",
+                                frames.len(),
+                                groups.len()
+                            ),
+                            stop,
+                        )?;
+                    }
+                    return Ok(Source::Stream(Box::new(inflate_frames(map, kind, groups, threadnum, cores))));
+                }
+                file.seek(SeekFrom::Start(0)).map_err(io_error(path))?;
+                Box::new(file)
+            }
+        }
+    };
+    // anything read as it comes might still be compressed, so peek at the start of it
+    let mut input = BufReader::new(input);
+    match compression(input.fill_buf().map_err(io_error(path))?) {
+        Some(kind) => {
+            if verbose {
+                write_verbose(format_args!("[+] Wordlist is {kind:?} compressed, decompressing on a separate thread"), stop)?;
+            }
+            let input = decoder(kind, input).map_err(io_error(path))?;
+            Ok(Source::Stream(Box::new(inflate_stream(input))))
+        }
+        None => Ok(Source::Stream(Box::new(input))),
+    }
+}
+/*}}}*/
+
+struct Shard {
+    // Structure to hold a chunk of the wordlist for a thread to search /*{{{*/
+    // Positions run on from one wordlist to the next, as if they were all one file
+    start: usize, // byte position of the chunk in the wordlists
+    end: usize,
+    line: usize, // line number the chunk starts on in its wordlist, if we're counting them
+    // the whole wordlist, or the piece of a stream the chunk is in, along with the
+    // lines either side of it if there's context to show, read straight out of
+    // rather than copied
+    map: Arc<Data>,
+    base: usize,   // byte position of the start of map in the wordlists
+    origin: usize, // byte position of the start of the chunk's wordlist
+    name: Option<Arc<[u8]>>, // the wordlist's name, when there's more than one
+}
+/*}}}*/
+
+struct ShardResult {
+    // Structure to hold a shard's output until it can be written in file order /*{{{*/
+    start: usize,
+    end: usize,
+    out: Vec<u8>,
+    // for each match, where its line ends in out, where it ends in the wordlist
+    // and its line number, so the writer can cut it short and add context after
+    ends: Vec<(usize, usize, usize)>,
+    map: Arc<Data>,
+    at: usize, // byte position of the start of map in its wordlist
+    name: Option<Arc<[u8]>>,
+}
+/*}}}*/
+
+struct Workers {
+    // Structure to hold our thread worker info /*{{{*/
+    threadnum: usize,
+    // whether each thread was pinned to its core
+    pinned: Vec<bool>,
+    // each says why if it couldn't carry on
+    threadhand: Vec<JoinHandle<Result<(), SingrepError>>>,
+    writer: Option<JoinHandle<Result<(), SingrepError>>>,
+    // tells the reader and threads to give up early
+    stop: Arc<AtomicBool>,
+    // where the shards the threads haven't finished with start, so the reader
+    // doesn't drop them from the cache while they're being read
+    in_flight: Arc<Mutex<BTreeSet<usize>>>,
+    // nanoseconds the threads have spent waiting for shards, for tuning the shard size
+    idle: Arc<AtomicU64>,
+    // lines of context the threads print before and after each match
+    before: usize,
+    after: usize,
+    // Dropping this closes the channel, which is how the threads know to exit
+    tx: crossbeam_channel::Sender<Shard>,
+    //rx: crossbeam_channel::Receiver<Option<Vec<u8>>>,
+    //tx2: crossbeam_channel::Sender<Stats>,
+    rx2: crossbeam_channel::Receiver<Stats>,
+    // value ids each thread newly found, sent after every shard
+    rx3: crossbeam_channel::Receiver<Vec<usize>>,
+}
+/*}}}*/
+
+struct Tuning {
+    // Where the shard size tuning was when it last looked /*{{{*/
+    at: Instant,
+    idle: Duration,    // how long the threads had idled by then
+    blocked: Duration, // how long the reader had blocked by then
+    read: usize,       // how many bytes had been read by then
+    rate: f64,         // bytes read a second over the window before
+    size: usize,       // the shard size over the window before
+    avoid: usize,      // a shard size that read slower, so isn't tried again
+}
+/*}}}*/
+
+#[derive(Clone)] // needed to send via channels between thread and main
+struct Stats {
+    // Structure to hold counters from the threads /*{{{*/
+    cracked: usize,
+    hashed: usize,
+    waited: Duration,  // how long the threads sat idle waiting for shards
+    blocked: Duration, // how long the reader waited for the threads to catch up
+    read: usize, // bytes read
+    // lines matched by each regex, when there's more than one
+    hits: Vec<usize>,
+    shard: usize, // the shard size the reader ended up with
+    // the sum of line_hash for every line found when verifying, to say which they were
+    checksum: u64,
+}
+/*}}}*/
+
+fn find(tofind: &ToFind, clear: &[u8]) -> Option<usize> {
+    // Exact matching routine, returns the id of the value matched /*{{{*/
+
+    // for small hashlists, can we get away with this cheaper check
+    // an empty line can only be an empty value, so goes straight to the lookup
+    if clear.first().is_some_and(|first| !tofind.start[*first as usize])
+    {
+        return None;
+    }
+    if clear.len() > 1 && !tofind.second[clear[1] as usize]
+    {
+        return None;
+    }
+
+    // check if the whole line is one of our values
+    if let Some(lines) = &tofind.lines {
+        return lines.get(clear).copied();
+    }
+    let input = Input::new(clear).anchored(Anchored::Yes);
+    if let Some(m) = tofind.automaton.find(input) {
+        if m.end() == clear.len() {
+            return Some(m.pattern().as_usize());
+        }
+    }
+
+    None
+}
+/*}}}*/
+
+fn find_all(tofind: &ToFind, clear: &[u8], tracked: &mut Tracked) -> bool {
+    // Substring matching routine that records every value seen in the line /*{{{*/
+    let mut any = false;
+    for m in tofind.automaton.find_overlapping_iter(clear) {
+        track_found(tracked, m.pattern().as_usize());
+        any = true;
+    }
+    any
+}
+/*}}}*/
+
+#[derive(Clone)]
+struct Matcher {
+    // Structure to hold everything a thread needs to decide if a line matches /*{{{*/
+    tofind: ToFind,
+    regexes: Vec<Regex>,
+    // more than one regex are matched together, and lines tagged with their names
+    set: Option<RegexSet>,
+    names: Vec<Vec<u8>>,
+    // finds the lines worth running the regexes on, or the lines with our values in
+    prefilter: Option<Prefilter>,
+    // whether anything the prefilter finds is a match, without checking the line
+    prefiltered: bool,
+    regex: bool,
+    exact: bool, // whole lines, either --exact or --intersect
+    invert: bool,
+    format: Option<Vec<u8>>, // template to fill from each regex match's capture groups
+}
+/*}}}*/
+
+struct Tracked {
+    // Structure to hold what a thread has found so far /*{{{*/
+    set: Vec<u64>,
+    new: Vec<usize>, // found since we last told the main thread
+}
+/*}}}*/
+
+fn track_found(tracked: &mut Tracked, id: usize) {
+    // Record a found value, remembering to send it on if it's new to us /*{{{*/
+    if !is_found(&tracked.set, id) {
+        mark_found(&mut tracked.set, id);
+        tracked.new.push(id);
+    }
+}
+/*}}}*/
+
+fn selected(matcher: &Matcher, clear: &[u8], folded: &mut Vec<u8>, tracked: Option<&mut Tracked>) -> bool {
+    // Decide if a line is one we want, recording which values it has if asked /*{{{*/
+    let tofind = &matcher.tofind;
+
+    // folded lines are compared with our folded values
+    let literal = match tofind.fold_lines && !matcher.regex {
+        true => {
+            fold_case(clear, tofind.fold, folded);
+            folded.as_slice()
+        }
+        false => clear,
+    };
+
+    let found = if let Some(set) = &matcher.set {
+        set.is_match(clear)
+    } else if matcher.regex {
+        matcher.regexes[0].is_match(clear)
+    } else if matcher.exact {
+        match (find(tofind, literal), tracked) {
+            (Some(id), Some(tracked)) => {
+                track_found(tracked, id);
+                true
+            }
+            (id, _) => id.is_some(),
+        }
+    } else if let Some(tracked) = tracked {
+        find_all(tofind, literal, tracked)
+    } else {
+        // Not exact match, look for any value within the line
+        tofind.automaton.is_match(literal)
+    };
+    found != matcher.invert
+}
+/*}}}*/
+
+fn matched_spans(
+    matcher: &Matcher,
+    clear: &[u8],
+    folded: &mut Vec<u8>,
+    patterns: &[usize],
+    spans: &mut Vec<(usize, usize, usize)>,
+) {
+    // Find where in a wanted line each match is and which regex made it, for printing only what matched /*{{{*/
+    spans.clear();
+    let tofind = &matcher.tofind;
+    if matcher.regex {
+        for pattern in patterns {
+            let re = &matcher.regexes[*pattern];
+            spans.extend(re.find_iter(clear).map(|m| (m.start(), m.end(), *pattern)));
+        }
+        spans.sort_unstable();
+    } else if let Some(leftmost) = &tofind.leftmost {
+        let unicode = tofind.fold_lines && tofind.fold == CaseFold::Unicode;
+        if unicode {
+            fold_case(clear, tofind.fold, folded);
+        }
+        let literal = if unicode { folded.as_slice() } else { clear };
+        for m in leftmost.find_iter(literal) {
+            let (start, end) = match unicode {
+                true => unfold_span(clear, m.start(), m.end()),
+                false => (m.start(), m.end()),
+            };
+            spans.push((start, end, 0));
+        }
+    } else {
+        // exact matches are the whole line
+        spans.push((0, clear.len(), 0));
+    }
+    // an empty match isn't worth printing
+    spans.retain(|(start, end, _)| start < end);
+}
+/*}}}*/
+
+fn nearest_before(matcher: &Matcher, map: &[u8], start: usize, lines: usize, folded: &mut Vec<u8>) -> Option<usize> {
+    // How many lines back from start the closest wanted line is, looking no further than lines /*{{{*/
+    let mut end = start; // just past the newline ending the line before
+    for distance in 1..=lines {
+        if end == 0 {
+            return None;
+        }
+        let line_start = memrchr(10, &map[..end - 1]).map_or(0, |i| i + 1);
+        if selected(matcher, &map[line_start..end - 1], folded, None) {
+            return Some(distance);
+        }
+        end = line_start;
+    }
+    None
+}
+/*}}}*/
+
+fn nearest_after(matcher: &Matcher, map: &[u8], end: usize, lines: usize, folded: &mut Vec<u8>) -> Option<usize> {
+    // How many lines on from end the closest wanted line is, looking no further than lines /*{{{*/
+    let mut start = end; // the start of the line after
+    for distance in 1..=lines {
+        if start >= map.len() {
+            return None;
+        }
+        let line_end = memchr(10, &map[start..]).map_or(map.len(), |i| start + i);
+        if selected(matcher, &map[start..line_end], folded, None) {
+            return Some(distance);
+        }
+        start = line_end + 1;
+    }
+    None
+}
+/*}}}*/
+
+fn prev_printed(matcher: &Matcher, map: &[u8], start: usize, after: usize, before: usize) -> bool {
+    // Whether the line before start gets printed, as a match or as context for one /*{{{*/
+    let mut folded = Vec::new();
+    nearest_before(matcher, map, start, after + 1, &mut folded).is_some()
+        || nearest_after(matcher, map, start, before, &mut folded).is_some()
+}
+/*}}}*/
+
+fn needs_separator(
+    first: usize,
+    last_printed: Option<usize>,
+    shard_first: usize,
+    shard_start: usize,
+    origin: usize,
+    prev_printed: impl FnOnce() -> bool,
+) -> bool {
+    // Whether a run of printed lines starting at line first needs a -- before it /*{{{*/
+    match last_printed {
+        Some(last) => first != last + 1,
+        // nothing can come before the start of the first wordlist
+        None if shard_start == 0 => false,
+        // the line before us is in the last wordlist, which may have printed anything
+        None if shard_start == origin => true,
+        // the line before us is in the last shard, which may have printed it
+        None if first == shard_first => !prev_printed(),
+        None => true,
+    }
+}
+/*}}}*/
+
+fn write_line(
+    out: &mut Vec<u8>,
+    name: Option<&[u8]>,
+    tag: Option<&[u8]>,
+    line: Option<usize>,
+    pos: Option<usize>,
+    clear: &[u8],
+    sep: u8,
+) {
+    // Write a line, prefixed by its wordlist, the regexes that matched, line number and position if asked, to our output /*{{{*/
+    if let Some(name) = name {
+        out.extend_from_slice(name);
+        out.extend_from_slice(&[sep]);
+    }
+    if let Some(tag) = tag {
+        out.extend_from_slice(tag);
+        out.extend_from_slice(&[sep]);
+    }
+    if let Some(line) = line {
+        write!(out, "{line}").unwrap();
+        // extend_from_slice is faster than push
+        out.extend_from_slice(&[sep]); // colon for a match, dash for context
+    }
+    if let Some(pos) = pos {
+        write!(out, "{pos}").unwrap();
+        out.extend_from_slice(&[sep]);
+    }
+    out.extend_from_slice(clear); // clear text
+    out.extend_from_slice(&[10]); // newline
+}
+/*}}}*/
+
+fn write_output(out: &[u8], started: &AtomicBool, stop: &AtomicBool) -> Result<(), SingrepError> {
+    // Write out a buffer, with no context separator at the very start of our output /*{{{*/
+    if out.is_empty() {
+        return Ok(());
+    }
+    let out = match started.swap(true, Ordering::Relaxed) {
+        false if out.starts_with(b"--\n") => &out[3..],
+        _ => out,
+    };
+    stdout().write_all(out).or_else(|e| output_failed(e, stop))
+}
+/*}}}*/
+
+fn output_failed(e: std::io::Error, stop: &AtomicBool) -> Result<(), SingrepError> {
+    // Stop everyone when our output can't be written, quietly if what's reading it has all it wants /*{{{*/
+    stop.store(true, Ordering::Relaxed);
+    match e.kind() {
+        std::io::ErrorKind::BrokenPipe => Ok(()),
+        _ => Err(SingrepError::Output(e)),
+    }
+}
+/*}}}*/
+
+fn write_verbose(message: std::fmt::Arguments, stop: &AtomicBool) -> Result<(), SingrepError> {
+    // Write a line of verbose output, which goes to the same place as the matches /*{{{*/
+    writeln!(stdout(), "{message}").or_else(|e| output_failed(e, stop))
+}
+/*}}}*/
+
+fn needle_count(tofind: &ToFind) -> usize {
+    // How many distinct values we're looking for /*{{{*/
+    match &tofind.lines {
+        Some(lines) => lines.len(),
+        None => tofind.values.len(),
+    }
+}
+/*}}}*/
+
+// A bitset of value ids that have been found, one bit per value
+fn mark_found(found: &mut [u64], id: usize) {
+    found[id / 64] |= 1 << (id % 64);
+}
+
+fn is_found(found: &[u64], id: usize) -> bool {
+    found[id / 64] & (1 << (id % 64)) != 0
+}
+
+struct Found {
+    // Structure to hold which values have been found across all threads /*{{{*/
+    bits: Vec<u64>,
+    count: usize,
+    total: usize,
+}
+/*}}}*/
+
+fn merge_found(found: &mut Found, ids: &[usize]) {
+    // Merge the ids a thread newly found into the overall set /*{{{*/
+    for id in ids {
+        if !is_found(&found.bits, *id) {
+            mark_found(&mut found.bits, *id);
+            found.count += 1;
+        }
+    }
+}
+/*}}}*/
+
+fn write_left(tofind: &ToFind, found: &[u64], path: &PathBuf) -> Result<(), SingrepError> {
+    // Write out the values never found, in the order they were given /*{{{*/
+    let mut left: Vec<(usize, &[u8])> = match &tofind.lines {
+        Some(lines) => lines
+            .iter()
+            .filter(|(_, id)| !is_found(found, **id))
+            .map(|(value, id)| (*id, tofind.unfolded.get(id).unwrap_or(value).as_slice()))
+            .collect(),
+        None => tofind
+            .values
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| !is_found(found, *id))
+            .map(|(id, value)| (id, value.as_slice()))
+            .collect(),
+    };
+    left.sort_unstable_by_key(|(id, _)| *id);
+
+    let failed = |e| match path.to_str() {
+        Some("-") => SingrepError::Output(e),
+        _ => SingrepError::Io(path.clone(), e),
+    };
+    let mut out: Box<dyn Write> = match path.to_str() {
+        Some("-") => Box::new(stdout().lock()),
+        _ => Box::new(File::create(path).map_err(failed)?),
+    };
+    let mut buf: Vec<u8> = Vec::with_capacity(8192);
+    for (_, value) in left {
+        buf.extend_from_slice(value);
+        buf.extend_from_slice(&[10]); // newline
+        if buf.len() >= 8192 {
+            out.write_all(&buf).map_err(failed)?;
+            buf.clear();
+        }
+    }
+    out.write_all(&buf).map_err(failed)?;
+    out.flush().map_err(failed)?;
+    Ok(())
+}
+/*}}}*/
+
+fn write_ordered(
+    rx: crossbeam_channel::Receiver<ShardResult>,
+    limit: Option<usize>,
+    stop: Arc<AtomicBool>,
+    started: Arc<AtomicBool>,
+    after: usize,
+    line_number: bool,
+    position: bool,
+) -> Result<(), SingrepError> {
+    // Write the shards' output in file order, stopping everyone once we hit the limit /*{{{*/
+    let mut pending: BTreeMap<usize, ShardResult> = BTreeMap::new();
+    let mut next = 0; // the start of the next shard to write
+    let mut left = limit.unwrap_or(usize::MAX);
+    // keep receiving after we're done so the threads' sends don't fail
+    for result in rx {
+        if left == 0 {
+            continue;
+        }
+        pending.insert(result.start, result);
+        while let Some(result) = pending.remove(&next) {
+            next = result.end;
+            let matches = result.ends.len().min(left);
+            left -= matches;
+            if left > 0 {
+                write_output(&result.out, &started, &stop)?;
+                continue;
+            }
+            if matches > 0 {
+                // the context after our last match, whether it matches or not
+                let (out_end, mut start, mut line) = result.ends[matches - 1];
+                let mut out = result.out[..out_end].to_vec();
+                for _ in 0..after {
+                    if start >= result.map.len() {
+                        break;
+                    }
+                    let end = memchr(10, &result.map[start..]).map_or(result.map.len(), |i| start + i);
+                    line += 1;
+                    write_line(
+                        &mut out,
+                        result.name.as_deref(),
+                        None,
+                        line_number.then_some(line),
+                        position.then_some(result.at + start),
+                        &result.map[start..end],
+                        45, // dash
+                    );
+                    start = end + 1;
+                }
+                write_output(&out, &started, &stop)?;
+            }
+            if left == 0 {
+                stop.store(true, Ordering::Relaxed);
+                pending.clear();
+                break;
+            }
+        }
+    }
+    stdout().flush().or_else(|e| output_failed(e, &stop))
+}
+/*}}}*/
+
+fn check_format(template: &str, re: &Regex) -> Result<(), SingrepError> {
+    // Make sure a template only refers to capture groups the regex has /*{{{*/
+    let names: HashSet<&str> = re.capture_names().flatten().collect();
+    let mut rest = template;
+    while let Some(at) = rest.find('$') {
+        rest = &rest[at + 1..];
+        // $$ is a literal dollar
+        if let Some(after) = rest.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+        let name = match rest.strip_prefix('{') {
+            // without its closing brace it's left as it is too
+            Some(braced) => braced.split_once('}').map(|(name, _)| name).unwrap_or_default(),
+            // the same [_0-9A-Za-z] names as Captures::expand, so $userç is $user then ç
+            None => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                &rest[..len]
+            }
+        };
+        // a $ without a name is left as it is
+        if name.is_empty() {
+            continue;
+        }
+        let known = match name.parse::<usize>() {
+            Ok(index) => index < re.captures_len(),
+            Err(_) => names.contains(name),
+        };
+        if !known {
+            return Err(SingrepError::Invalid(format!(
+                "--format refers to ${name}, which isn't a capture group in the regex"
+            )));
+        }
+    }
+    Ok(())
+}
+/*}}}*/
+
+fn parse_cores(list: &str) -> Result<Vec<usize>, SingrepError> {
+    // Turn a list of cores and ranges of them, like 0-3,6, into the cores /*{{{*/
+    let available: HashSet<usize> = core_affinity::get_core_ids()
+        .ok_or_else(|| SingrepError::Invalid("can't find the cores to pin threads to".to_string()))?
+        .into_iter()
+        .map(|core| core.id)
+        .collect();
+    let mut cores = Vec::new();
+    for part in list.split(',') {
+        let (from, to) = part.split_once('-').unwrap_or((part, part));
+        let core = |c: &str| {
+            c.trim()
+                .parse::<usize>()
+                .map_err(|_| SingrepError::Invalid(format!("{part} isn't a core or range of cores")))
+        };
+        let (from, to) = (core(from)?, core(to)?);
+        if from > to {
+            return Err(SingrepError::Invalid(format!("{part} is a range of cores that runs backwards")));
+        }
+        cores.extend(from..=to);
+    }
+    if cores.is_empty() {
+        return Err(SingrepError::Invalid(format!("no cores to pin to in {list}")));
+    }
+    if let Some(core) = cores.iter().find(|core| !available.contains(core)) {
+        return Err(SingrepError::Invalid(format!(
+            "can't pin to core {core}, there are only {} available",
+            available.len()
+        )));
+    }
+    Ok(cores)
+}
+/*}}}*/
+
+fn pin_to(cores: &[usize], thread: usize) -> bool {
+    // Pin the current thread to its turn of the cores, if we were given any, saying if it was /*{{{*/
+    if cores.is_empty() {
+        return false;
+    }
+    let core = cores[thread % cores.len()];
+    let pinned = core_affinity::set_for_current(core_affinity::CoreId { id: core });
+    if !pinned {
+        // the search carries on just as well unpinned
+        eprintln!("singrep: couldn't pin a thread to core {core}, it's free to run anywhere");
+    }
+    pinned
+}
+/*}}}*/
+
+fn meminfo(field: &str) -> Option<usize> {
+    // Read a field from /proc/meminfo in bytes /*{{{*/
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with(field))?;
+    let kb: usize = line[field.len()..].trim_start_matches(':').trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+/*}}}*/
+
+fn cgroup_headroom() -> Option<usize> {
+    // How much more memory our cgroup lets us use, page cache included /*{{{*/
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let mut candidates = Vec::new();
+    for line in cgroups.lines() {
+        // cgroup v2 has a single hierarchy, v1 has one for memory
+        if let Some(path) = line.strip_prefix("0::") {
+            candidates.push(format!("/sys/fs/cgroup{path}/memory.max|/sys/fs/cgroup{path}/memory.current"));
+        } else if let Some((_, path)) = line.split_once(":memory:") {
+            candidates.push(format!(
+                "/sys/fs/cgroup/memory{path}/memory.limit_in_bytes|/sys/fs/cgroup/memory{path}/memory.usage_in_bytes"
+            ));
+        }
+    }
+    // inside a container the hierarchy is often mounted at its root
+    candidates.push("/sys/fs/cgroup/memory.max|/sys/fs/cgroup/memory.current".to_string());
+    candidates.push(
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes|/sys/fs/cgroup/memory/memory.usage_in_bytes".to_string(),
+    );
+    let read = |path: &str| std::fs::read_to_string(path).ok()?.trim().parse::<usize>().ok();
+    for candidate in candidates {
+        let (limit, usage) = candidate.split_once('|').unwrap();
+        // "max", or a number too big to be real, means there's no limit
+        if let Some(limit) = read(limit).filter(|limit| *limit < 1 << 60) {
+            return Some(limit.saturating_sub(read(usage).unwrap_or(0)));
+        }
+    }
+    None
+}
+/*}}}*/
+
+fn auto_tune(args: &mut Args, threadnum: usize) {
+    // Pick block, cache and shard sizes for this machine, rather than the defaults /*{{{*/
+    let page = page_size::get();
+    // the file cache can use what's available, unless a cgroup holds us to less
+    let memory = match (meminfo("MemAvailable"), cgroup_headroom()) {
+        (Some(available), Some(headroom)) => Some(available.min(headroom)),
+        (available, headroom) => available.or(headroom),
+    };
+    if let Some(memory) = memory {
+        // leave room for everything else, our cache window is read a half at a time
+        args.cache = (memory / 2 / page * page).max(64 * 1024 * 1024);
+    }
+    // reads need to be big enough to keep the disk busy, but fit many to a cache window
+    args.block = (args.block.min(args.cache / 16) / page * page).max(page);
+    // start with a few shards a thread per block, the reader adjusts this as it goes
+    args.shard = (args.block / 4).clamp(64 * 1024, shard_limit(args.cache, threadnum));
+    // say what we picked, on stderr so it's kept apart from what we find
+    eprintln!(
+        "[+] Auto-tuned for {} memory and {page} byte pages: block {}, cache {}, shard {}",
+        memory.map_or("unknown".to_string(), |memory| memory.to_string()),
+        args.block,
+        args.cache,
+        args.shard
+    );
+}
+/*}}}*/
+
+fn shard_limit(cache: usize, threadnum: usize) -> usize {
+    // The biggest a shard can be tuned to, so every thread has a few in the cache window /*{{{*/
+    (cache / 2 / (threadnum * 4)).clamp(64 * 1024, 64 * 1024 * 1024)
+}
+/*}}}*/
+
+fn match_limit(args: &Args) -> Option<usize> {
+    // How many matches we're allowed to output /*{{{*/
+    match args.first {
+        true => Some(1),
+        false => args.max_count,
+    }
+}
+/*}}}*/
+
+fn setup_workers(matcher: Matcher, args: &Args, threadnum: usize, cores: &[usize]) -> Workers {
+    // Fire off our worker threads to wait for the data from the wordlist /*{{{*/
+    let mut threadhand: Vec<JoinHandle<_>> = Vec::new();
+    let (pinned_tx, pinned_rx) = unbounded();
+
+    // We clone the reciever multiple times which is how the threads pick up new clears
+    // Can't do that with mpsc which only allows cloning the sender, need crossbeam.
+    // It's bounded so the reader can't get too far ahead of the threads.
+    let (tx, rx): (
+        crossbeam_channel::Sender<Shard>,
+        crossbeam_channel::Receiver<Shard>,
+    ) = bounded(threadnum * 4);
+    let (tx2, rx2): (
+        crossbeam_channel::Sender<Stats>,
+        crossbeam_channel::Receiver<Stats>,
+    ) = unbounded();
+    let (tx3, rx3): (
+        crossbeam_channel::Sender<Vec<usize>>,
+        crossbeam_channel::Receiver<Vec<usize>>,
+    ) = unbounded();
+
+    // With a limit on matches, the earliest matches need to be written in order
+    // by a dedicated thread rather than by whichever thread finds them first
+    let limit = match_limit(args);
+    let ordered = args.ordered || limit.is_some();
+    let stop = Arc::new(AtomicBool::new(limit == Some(0)));
+    let in_flight = Arc::new(Mutex::new(BTreeSet::new()));
+    let idle = Arc::new(AtomicU64::new(0));
+    let (tx4, rx4): (
+        crossbeam_channel::Sender<ShardResult>,
+        crossbeam_channel::Receiver<ShardResult>,
+    ) = unbounded();
+    // -C sets both sides of the context unless they're given on their own
+    let after = args.after_context.or(args.context).unwrap_or(0);
+    let before = args.before_context.or(args.context).unwrap_or(0);
+    // whether anything has been written yet, context separators only go between
+    let started = Arc::new(AtomicBool::new(false));
+    let writer = match ordered {
+        true => {
+            let stop_writer = stop.clone();
+            let started_writer = started.clone();
+            let (line_number, position) = (args.line_number, args.position);
+            Some(thread::spawn(move || {
+                write_ordered(rx4, limit, stop_writer, started_writer, after, line_number, position)
+            }))
+        }
+        false => None,
+    };
+    // only keep track of which values were found if we need to
+    let found_words = match args.left.is_some() || args.first_each {
+        true => needle_count(&matcher.tofind).div_ceil(64),
+        false => 0,
+    };
+    let context = (after > 0 || before > 0) && !args.count;
+
+    for j in 0..threadnum {
+        // Make copies of these two for the threads
+        let rx_thread = rx.clone();
+        let tx2_thread = tx2.clone();
+        let tx3_thread = tx3.clone();
+        let tx4_thread = tx4.clone();
+        let stop_thread = stop.clone();
+        let in_flight_thread = in_flight.clone();
+        let idle_thread = idle.clone();
+        let started_thread = started.clone();
+        let matcher_thread = matcher.clone();
+        let args_position = args.position;
+        let args_line_number = args.line_number;
+        let args_verify = args.verify;
+        let args_count = args.count;
+        let args_only_matching = args.only_matching;
+        let cores_thread = cores.to_vec();
+        let pinned_thread = pinned_tx.clone();
+        //let to_find_thread = hashes.hashlist.clone();
+        threadhand.push(thread::spawn(move || {
+            // The in-thread worker code /*{{{*/
+            // the main thread only reports the cores we actually got
+            let _ = pinned_thread.send((j, pin_to(&cores_thread, j)));
+            // Pre-allocate to reduce alloc overhead
+            let mut out: Vec<u8> = Vec::with_capacity(8192);
+            // what this thread has found so far, so each id is only sent once
+            let mut tracked = Tracked {
+                set: vec![0; found_words],
+                new: Vec::new(),
+            };
+            let track = found_words > 0;
+            let mut folded: Vec<u8> = Vec::new();
+            let mut spans: Vec<(usize, usize, usize)> = Vec::new();
+            let mut patterns: Vec<usize> = Vec::new();
+            let mut tag: Vec<u8> = Vec::new();
+            // an inverted line matched none of them, so has nothing to be tagged with
+            let tagged = matcher_thread.set.is_some() && !matcher_thread.invert;
+            // without anything to do for lines that don't match, they can be skipped over
+            let skip_lines = !matcher_thread.invert && !context && !track;
+            let mut filled: Vec<u8> = Vec::new();
+            // lines we haven't printed since the last one we did, in case they're
+            // needed as context before a match: (line, start, end) within the shard
+            let mut pending: VecDeque<(usize, usize, usize)> = VecDeque::with_capacity(before + 1);
+            let mut stats = Stats {
+                cracked: 0,
+                hashed: 0,
+                waited: Duration::ZERO,
+                blocked: Duration::ZERO, // not used here
+                read: 0,                 // not used here
+                hits: vec![0; matcher_thread.set.as_ref().map_or(0, RegexSet::len)],
+                shard: 0, // not used here
+                checksum: 0,
+            };
+
+            // Fetch clears from the channel, until the reader's done and it's closed
+            loop {
+                let waiting = Instant::now();
+                let Ok(shard) = rx_thread.recv() else {
+                    break;
+                };
+                let waited = waiting.elapsed();
+                stats.waited += waited;
+                idle_thread.fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+                // someone else found everything we need, don't bother
+                if stop_thread.load(Ordering::Relaxed) {
+                    in_flight_thread.lock().unwrap().remove(&shard.start);
+                    // the ordered writer still needs to hear of it, to write the shards after it
+                    if ordered {
+                        let _ = tx4_thread.send(ShardResult {
+                            start: shard.start,
+                            end: shard.end,
+                            out: Vec::new(),
+                            ends: Vec::new(),
+                            at: shard.base - shard.origin,
+                            map: shard.map,
+                            name: shard.name,
+                        });
+                    }
+                    continue;
+                }
+                let Shard { start: shard_start, end: shard_end, mut line, map, base, origin, name: shard_name } = shard;
+                let message = &map[shard_start - base..shard_end - base];
+                let name = shard_name.as_deref();
+                // where we are in our own wordlist, for positions, and in the map, for looking around us
+                let file_start = shard_start - origin;
+                let map_start = shard_start - base;
+                let first_line = line + 1;
+                let mut ends: Vec<(usize, usize, usize)> = Vec::new();
+                let mut matches = 0; // how many we wanted in this shard
+                let mut last_printed: Option<usize> = None;
+                let mut after_left = 0;
+                pending.clear();
+                if context && after > 0 {
+                    // a match at the end of the last shard can reach into ours
+                    if let Some(distance) =
+                        nearest_before(&matcher_thread, &map, map_start, after, &mut folded)
+                    {
+                        after_left = after + 1 - distance;
+                    }
+                }
+
+                let mut offset = 0; // where we are in the shard
+                // where the prefilter next found something, lines before it can't match
+                let mut candidate = matcher_thread
+                    .prefilter
+                    .as_ref()
+                    .and_then(|prefilter| next_candidate(prefilter, message, 0));
+                while offset < message.len() {
+                    if let Some(prefilter) = &matcher_thread.prefilter {
+                        if candidate.is_some_and(|at| at < offset) {
+                            candidate = next_candidate(prefilter, message, offset);
+                        }
+                        if skip_lines {
+                            // jump straight to the line with the next candidate in it
+                            let to = match candidate {
+                                Some(at) => memrchr(10_u8, &message[offset..at]).map_or(offset, |nl| offset + nl + 1),
+                                None => message.len(),
+                            };
+                            let (lines, empty) = count_skipped(&message[offset..to]);
+                            line += lines;
+                            stats.hashed += lines - empty;
+                            offset = to;
+                            if candidate.is_none() {
+                                break;
+                            }
+                        }
+                    }
+                    // empty lines still count towards our position
+                    let clear_start = offset;
+                    let clear_end = memchr(10_u8, &message[offset..]).map_or(message.len(), |nl| offset + nl);
+                    let clear = &message[clear_start..clear_end];
+                    offset = clear_end + 1;
+                    line += 1;
+                    //println!("Thread {} recieved: '{:?}'",j,std::str::from_utf8(clear));
+
+                    if !clear.is_empty() {
+                        stats.hashed += 1;
+                    }
+                    let tracking = match track {
+                        true => Some(&mut tracked),
+                        false => None,
+                    };
+                    // a line the prefilter found nothing in can't match
+                    let found = match matcher_thread.prefilter.is_some() {
+                        true if candidate.is_none_or(|at| at >= clear_end) => matcher_thread.invert,
+                        true if matcher_thread.prefiltered && !track => !matcher_thread.invert,
+                        _ => selected(&matcher_thread, clear, &mut folded, tracking),
+                    };
+                    if !found && !context {
+                        continue;
+                    }
+
+                    if found {
+                        stats.cracked += 1;
+                        if args_verify {
+                            stats.checksum = stats.checksum.wrapping_add(line_hash(shard_start + clear_start, line, clear));
+                        }
+                        matches += 1;
+                        // which of our regexes matched, an inverted line has none
+                        patterns.clear();
+                        if let Some(set) = &matcher_thread.set {
+                            patterns.extend(set.matches(clear).iter());
+                            tag.clear();
+                            for pattern in &patterns {
+                                stats.hits[*pattern] += 1;
+                                if !tag.is_empty() {
+                                    tag.extend_from_slice(&[44]); // comma
+                                }
+                                tag.extend_from_slice(&matcher_thread.names[*pattern]);
+                            }
+                        } else if matcher_thread.regex {
+                            patterns.push(0);
+                        }
+                        if !args_count {
+                            // the lines before us, then us
+                            let skip = pending.len().saturating_sub(before);
+                            let first = pending.get(skip).map_or(line, |p| p.0);
+                            if context
+                                && needs_separator(first, last_printed, first_line, shard_start, origin, || {
+                                    prev_printed(&matcher_thread, &map, map_start, after, before)
+                                })
+                            {
+                                out.extend_from_slice(b"--\n");
+                            }
+                            for (before_line, start, end) in pending.drain(..).skip(skip) {
+                                write_line(
+                                    &mut out,
+                                    name,
+                                    None,
+                                    args_line_number.then_some(before_line),
+                                    args_position.then_some(file_start + start),
+                                    &message[start..end],
+                                    45, // dash
+                                );
+                            }
+                            if let Some(template) = &matcher_thread.format {
+                                for pattern in &patterns {
+                                    let re = &matcher_thread.regexes[*pattern];
+                                    for caps in re.captures_iter(clear) {
+                                        let whole = caps.get(0).unwrap();
+                                        if whole.is_empty() {
+                                            continue;
+                                        }
+                                        filled.clear();
+                                        caps.expand(template, &mut filled);
+                                        write_line(
+                                            &mut out,
+                                            name,
+                                            tagged.then(|| matcher_thread.names[*pattern].as_slice()),
+                                            args_line_number.then_some(line),
+                                            args_position.then_some(file_start + clear_start + whole.start()),
+                                            &filled,
+                                            58, // colon
+                                        );
+                                    }
                                 }
-                                // update the main process on progress
-                                if stats.cracked == updatethresh {
-                                    tx2_thread.send(stats).unwrap();
-                                    stats.cracked = 0;
-                                    stats.hashed = 0;
+                            } else if args_only_matching {
+                                matched_spans(&matcher_thread, clear, &mut folded, &patterns, &mut spans);
+                                for (start, end, pattern) in &spans {
+                                    write_line(
+                                        &mut out,
+                                        name,
+                                        tagged.then(|| matcher_thread.names[*pattern].as_slice()),
+                                        args_line_number.then_some(line),
+                                        args_position.then_some(file_start + clear_start + start),
+                                        &clear[*start..*end],
+                                        58, // colon
+                                    );
                                 }
+                            } else {
+                                write_line(
+                                    &mut out,
+                                    name,
+                                    tagged.then_some(tag.as_slice()),
+                                    args_line_number.then_some(line),
+                                    args_position.then_some(file_start + clear_start),
+                                    clear,
+                                    58, // colon
+                                );
+                            }
+                            last_printed = Some(line);
+                        }
+                        after_left = after;
+                        if ordered {
+                            // the writer needs our output in one piece, and
+                            // later matches in this shard might not be needed
+                            ends.push((out.len(), map_start + offset, line));
+                            if limit.is_some_and(|limit| matches >= limit) {
+                                break;
                             }
                         }
+                    } else if after_left > 0 {
+                        after_left -= 1;
+                        // we're only the first line printed if the last shard's match reached us
+                        if needs_separator(line, last_printed, first_line, shard_start, origin, || true) {
+                            out.extend_from_slice(b"--\n");
+                        }
+                        write_line(
+                            &mut out,
+                            name,
+                            None,
+                            args_line_number.then_some(line),
+                            args_position.then_some(file_start + clear_start),
+                            clear,
+                            45, // dash
+                        );
+                        last_printed = Some(line);
+                    } else if before > 0 {
+                        if pending.len() == before {
+                            pending.pop_front();
+                        }
+                        pending.push_back((line, clear_start, clear_start + clear.len()));
+                    }
+
+                    if !ordered && out.len() >= 8192 {
+                        // check if our output buffer should be flushed
+                        // make sure this comparison aligns with capacity
+                        write_output(&out, &started_thread, &stop_thread)?;
+                        out.clear();
+                    }
+                }
+                let limited = limit.is_some_and(|limit| matches >= limit);
+                if context && !limited && !pending.is_empty() {
+                    // a match at the start of the next shard can reach back into ours
+                    if let Some(distance) =
+                        nearest_after(&matcher_thread, &map, shard_end - base, before, &mut folded)
+                    {
+                        let skip = pending.len().saturating_sub(before + 1 - distance);
+                        let first = pending[skip].0;
+                        if needs_separator(first, last_printed, first_line, shard_start, origin, || {
+                            prev_printed(&matcher_thread, &map, map_start, after, before)
+                        }) {
+                            out.extend_from_slice(b"--\n");
+                        }
+                        for (before_line, start, end) in pending.drain(..).skip(skip) {
+                            write_line(
+                                &mut out,
+                                name,
+                                None,
+                                args_line_number.then_some(before_line),
+                                args_position.then_some(file_start + start),
+                                &message[start..end],
+                                45, // dash
+                            );
+                        }
                     }
                 }
-                while rx_thread.is_empty() {
-                    stats.waits += 1;
-                    //write!(&stdout(),"{}.",count).unwrap();
-                    thread::sleep(std::time::Duration::from_millis(stats.waits as u64));
+                if ordered {
+                    // the writer only hangs up when it can't write, and says so itself
+                    let _ = tx4_thread
+                        .send(ShardResult {
+                            start: shard_start,
+                            end: shard_end,
+                            out: std::mem::take(&mut out),
+                            ends,
+                            map,
+                            at: base - origin,
+                            name: shard_name.clone(),
+                        });
+                }
+                // the reader is free to drop our part of the wordlist from the cache
+                in_flight_thread.lock().unwrap().remove(&shard_start);
+                // let the main thread know which values we found for the first time
+                if !tracked.new.is_empty() {
+                    tx3_thread.send(std::mem::take(&mut tracked.new))?;
                 }
             }
+            // We're done, dump our buffer and exit
+            write_output(&out, &started_thread, &stop_thread)?;
+            tx2_thread.send(stats)?;
+            Ok(())
         }));
         /*}}}*/
     }
+    // which threads got the cores they were given, they each say as they start
+    let mut pinned = vec![false; threadnum];
+    if !cores.is_empty() {
+        for _ in 0..threadnum {
+            let Ok((j, ok)) = pinned_rx.recv() else {
+                break;
+            };
+            pinned[j] = ok;
+        }
+    }
     Workers {
         threadnum,
+        pinned,
         threadhand,
+        writer,
+        stop,
+        in_flight,
+        idle,
+        before: if context { before } else { 0 },
+        after: if context { after } else { 0 },
         tx,
         //rx: rx,
         //tx2: tx2,
         rx2,
+        rx3,
+    }
+}
+/*}}}*/
+
+fn count_lines(chunk: &[u8]) -> usize {
+    // Count the newlines in a chunk, simple enough for the compiler to vectorise /*{{{*/
+    chunk.iter().filter(|c| **c == 10_u8).count()
+}
+/*}}}*/
+
+fn tuning(workers: &Workers, stats: &Stats) -> Tuning {
+    // Start tuning the shard size from where the threads and reader are now /*{{{*/
+    Tuning {
+        at: Instant::now(),
+        idle: Duration::from_nanos(workers.idle.load(Ordering::Relaxed)),
+        blocked: stats.blocked,
+        read: stats.read,
+        rate: 0.0,
+        size: stats.shard,
+        avoid: 0,
+    }
+}
+/*}}}*/
+
+fn tune_shard(tuned: &mut Tuning, workers: &Workers, stats: &Stats, chunk_size: usize, limit: usize) -> usize {
+    // Size shards by how long the threads sat idle since we last looked, and how fast we read /*{{{*/
+    let idle = Duration::from_nanos(workers.idle.load(Ordering::Relaxed));
+    let window = tuned.at.elapsed().as_secs_f64();
+    let idle_share = (idle - tuned.idle).as_secs_f64() / (window * workers.threadnum as f64);
+    let blocked_share = (stats.blocked - tuned.blocked).as_secs_f64() / window;
+    let rate = (stats.read - tuned.read) as f64 / window;
+    let grown = (chunk_size * 2).min(limit);
+    let shrunk = (chunk_size / 2).max(64 * 1024);
+    let next = if chunk_size != tuned.size && rate < tuned.rate * 0.9 {
+        // the last change read less a second than before it, so go back and don't try it again
+        tuned.avoid = chunk_size;
+        tuned.size
+    } else if idle_share > 0.1 && grown != tuned.avoid {
+        // idle threads are waiting on us, so bigger shards cut our overhead per byte
+        grown
+    } else if idle_share < 0.01 && blocked_share > 0.9 && shrunk != tuned.avoid {
+        // busy threads keeping us waiting can share out smaller shards more evenly
+        shrunk
+    } else {
+        chunk_size
+    };
+    *tuned = Tuning {
+        at: Instant::now(),
+        idle,
+        blocked: stats.blocked,
+        read: stats.read,
+        rate,
+        size: chunk_size,
+        avoid: tuned.avoid,
+    };
+    next
+}
+/*}}}*/
+
+fn all_found(workers: &Workers, args: &Args, found: &mut Found) -> bool {
+    // Merge what the threads have found, and with --first-each stop them once it's everything /*{{{*/
+    while let Ok(ids) = workers.rx3.try_recv() {
+        merge_found(found, &ids);
+    }
+    if args.first_each && found.count == found.total {
+        // the threads skip whatever's still queued, like they do for --first
+        workers.stop.store(true, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+/*}}}*/
+
+fn read_more(input: &mut dyn Read, buffer: &mut Vec<u8>, wanted: usize, path: &Path) -> Result<bool, SingrepError> {
+    // Read some more of a stream onto the end of a buffer, with room for at least wanted bytes, saying if it's ended /*{{{*/
+    let filled = buffer.len();
+    buffer.resize(wanted.max(filled + 65_536), 0);
+    let read = loop {
+        match input.read(&mut buffer[filled..]) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            read => break read,
+        }
+    };
+    // leave the buffer as it was if the read failed
+    buffer.truncate(filled + *read.as_ref().unwrap_or(&0));
+    Ok(read.map_err(io_error(path))? == 0)
+}
+/*}}}*/
+
+fn read_stream(
+    mut input: Box<dyn Read>,
+    workers: &Workers,
+    args: &Args,
+    found: &mut Found,
+    stats: &mut Stats,
+    name: Option<Arc<[u8]>>,
+    path: &Path,
+) -> Result<(), SingrepError> {
+    // Read a stream into a ring of buffers, and send them to the worker threads cut on newlines /*{{{*/
+    let origin = stats.read; // the stream starts where the last wordlist ended
+    let mut count = 1; // how many shards we've sent
+    let check_thresh = 50; // how often to retune the shard size
+    let mut chunk_size = stats.shard;
+    let mut tuned = tuning(workers, stats);
+
+    // Buffers come back once the threads are done with them, there's one for
+    // each shard that can be queued or searched, and one for us to fill
+    let (free_tx, free_rx) = unbounded();
+    let most = workers.threadnum * 5 + 1;
+    let mut buffers = 0;
+    let mut carry: Vec<u8> = Vec::new(); // the start of a line the last buffer cut off
+    // The lines around a shard are gone by the time a thread looks for context in them,
+    // so each buffer gets copies of the lines the threads and the ordered writer might
+    // look back on before its shard, and ahead to after it
+    let (look_back, look_ahead) = match workers.before > 0 || workers.after > 0 {
+        true => (workers.after + 1, workers.before.max(workers.after)),
+        false => (0, 0),
+    };
+    let mut behind: Vec<u8> = Vec::new(); // the last look_back lines before pos
+    let mut pos = 0; // how far into the stream we are
+    let mut line = 0; // how many lines came before pos, only counted if needed
+    let mut eof = false;
+    // the lines looked ahead to can leave some of the stream in carry after it's ended
+    while !eof || !carry.is_empty() {
+        // stop reading if the threads have found all we need
+        if workers.stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let mut buffer: Vec<u8> = match free_rx.try_recv() {
+            Ok(buffer) => buffer,
+            Err(_) if buffers < most => {
+                buffers += 1;
+                Vec::with_capacity(chunk_size)
+            }
+            Err(_) => free_rx.recv()?,
+        };
+        buffer.clear();
+        buffer.extend_from_slice(&behind);
+        let from = buffer.len(); // where the shard starts in the buffer
+        buffer.append(&mut carry);
+        // fill it with a shard's worth, then up to the end of a line
+        while !eof {
+            if buffer.len() - from >= chunk_size {
+                if let Some(nl) = memrchr(10_u8, &buffer[from..]) {
+                    carry.extend_from_slice(&buffer[from + nl + 1..]);
+                    buffer.truncate(from + nl + 1);
+                    break;
+                }
+            }
+            eof = read_more(&mut input, &mut buffer, from + chunk_size, path)?;
+        }
+        if buffer.len() == from {
+            break;
+        }
+        let to = pos + buffer.len() - from;
+        let lines = match args.line_number || args.verify {
+            true => count_lines(&buffer[from..]),
+            false => 0,
+        };
+        if look_back > 0 {
+            // the lines before the next shard, the last of them ends ours
+            let end = buffer.len() - 1;
+            let start = memrchr_iter(10_u8, &buffer[..end]).nth(look_back - 1).map_or(0, |nl| nl + 1);
+            behind.clear();
+            behind.extend_from_slice(&buffer[start..]);
+        }
+        if look_ahead > 0 {
+            // read on until the lines after ours are in carry, or there are no more
+            while !eof && memchr_iter(10_u8, &carry).nth(look_ahead - 1).is_none() {
+                let wanted = carry.len() + 1;
+                eof = read_more(&mut input, &mut carry, wanted, path)?;
+            }
+            let ahead = memchr_iter(10_u8, &carry).nth(look_ahead - 1).map_or(carry.len(), |nl| nl + 1);
+            buffer.extend_from_slice(&carry[..ahead]);
+        }
+        // send it to the threads, who'll hand the buffer back when they're done
+        workers.in_flight.lock().unwrap().insert(origin + pos);
+        let sending = Instant::now();
+        workers.tx.send(Shard {
+            start: origin + pos,
+            end: origin + to,
+            line,
+            map: Arc::new(Data::Buffer(buffer, free_tx.clone())),
+            base: origin + pos - from,
+            origin,
+            name: name.clone(),
+        })?;
+        stats.blocked += sending.elapsed();
+        line += lines;
+        stats.read += to - pos;
+        pos = to;
+        // check if we can exit early because every value has turned up somewhere
+        if all_found(workers, args, found) {
+            break;
+        }
+        // only retune sometimes, so there's been time for it to make a difference
+        if args.auto_tune && count % check_thresh == 0 {
+            // with no cache window to fit in, the buffers are the memory we're using
+            chunk_size = tune_shard(&mut tuned, workers, stats, chunk_size, 16 * 1024 * 1024);
+            stats.shard = chunk_size;
+        }
+        count += 1;
     }
+    Ok(())
 }
 /*}}}*/
 
 fn read_wordlist(
     wordlist: &mut Wordlist,
-    chunk_size: usize,
     workers: &Workers,
-    first: bool,
-    block_size: usize,
-) -> Result<Stats, Box<dyn Error>> {
+    args: &Args,
+    found: &mut Found,
+    stats: &mut Stats,
+    name: Option<Arc<[u8]>>,
+) -> Result<(), SingrepError> {
     // Read the wordlist, send chunks to the worker threads & handle cache'ing /*{{{*/
-    let mut stats = Stats {
-        cracked: 0, // how many have we cracked
-        hashed: 0,  // how many hashes have we generated
-        waits: 0,   // how many times was a thread waiting
-        kbs: 0,     // amount of data read for perf stats
-    };
-    let mut count = 1; // optimisation counter to reduce expensive thread checkins
-    let check_thresh = 50; // how often to check with the threads
+    let origin = stats.read; // the wordlist starts where the last one ended
+    let mut count = 1; // how many shards we've sent
+    let check_thresh = 50; // how often to retune the shard size
+    let mut chunk_size = stats.shard;
+    let mut tuned = tuning(workers, stats);
 
     // Send chunks of the wordlist to the threads to deal with, but split on newlines
     let mut pos = 0; // our current pointer/index into the wordlist
-    while pos < wordlist.length - 1 {
+    let mut line = 0; // how many lines came before pos, only counted if needed
+    while pos < wordlist.length {
+        // stop reading if the threads have found all we need
+        if workers.stop.load(Ordering::Relaxed) {
+            break;
+        }
         // advance the cursor but not past the end of the file
         let mut to = match pos {
             e if e + chunk_size >= wordlist.length => wordlist.length,
@@ -435,49 +2559,64 @@ fn read_wordlist(
         while wordlist.mmap[to - 1] != 10 && to < wordlist.length {
             to += 1;
         }
-        // send it to the threads
-        workers
-            .tx
-            .send(Some((pos, wordlist.mmap[pos..to].to_vec())))?;
+        // send it to the threads, which read it straight from the map
+        workers.in_flight.lock().unwrap().insert(origin + pos);
+        let sending = Instant::now();
+        workers.tx.send(Shard {
+            start: origin + pos,
+            end: origin + to,
+            line,
+            map: wordlist.mmap.clone(),
+            base: origin,
+            origin,
+            name: name.clone(),
+        })?;
+        stats.blocked += sending.elapsed();
+        // the threads work out of order, so keep a running count of lines for them
+        if args.line_number || args.verify {
+            line += count_lines(&wordlist.mmap[pos..to]);
+        }
         // update the bytes counter
-        stats.kbs += (to - pos) / 1024;
-        // update the cursor position
-        pos = to - 1;
-        // only checkin with threads sometimes to prevent slowdowns
-        if count % check_thresh == 0 {
-            // check if we can exit early because we cracked everything
-            if let Ok(recv_stats) = workers.rx2.try_recv() {
-                stats.cracked += recv_stats.cracked;
-                stats.hashed += recv_stats.hashed;
-                stats.waits += recv_stats.waits;
-                // if we can exit early stop reading the wordlist and try exit
-                if stats.cracked == 1 && first {
-                    break;
-                }
-            }
+        stats.read += to - pos;
+        // update the cursor position, the next shard starts after our newline
+        pos = to;
+        // check if we can exit early because every value has turned up somewhere
+        if all_found(workers, args, found) {
+            break;
+        }
+        // only retune sometimes, so there's been time for it to make a difference
+        if args.auto_tune && count % check_thresh == 0 {
+            let limit = shard_limit(wordlist.cache_size, workers.threadnum);
+            chunk_size = tune_shard(&mut tuned, workers, stats, chunk_size, limit);
+            stats.shard = chunk_size;
         }
         count += 1;
 
         // Once we've read half the cache'd data, drop the first half, and cache ahead another half
         if pos % (wordlist.cache_size / 2) <= chunk_size && wordlist.cache_point < wordlist.length {
-            // Drop the first half of the cache'd data
-            #[cfg(target_os = "macos")]
-            uncache(&wordlist.mmap, pos);
-            #[cfg(target_os = "linux")]
-            uncache(&wordlist.file, &mut wordlist.mmap, pos);
+            // Drop the first half of the cache'd data, but not what the threads are still reading,
+            // shards still in flight from an earlier wordlist don't hold any of ours
+            let done = workers.in_flight.lock().unwrap().range(origin..).next().map_or(pos, |start| start - origin);
+            // nothing of ours is done yet, and a length of 0 would drop the whole file
+            if done > 0 {
+                #[cfg(target_os = "macos")]
+                uncache(&wordlist.mmap, done)?;
+                #[cfg(target_os = "linux")]
+                uncache(&wordlist.file, &mut wordlist.mmap, done)?;
+            }
 
             // Cache the next half block
             let _elapsed_time = cache_file(
                 &mut wordlist.file,
                 wordlist.cache_size / 2,
-                block_size,
+                args.block,
                 wordlist.cache_point as u64,
-            );
+            )?;
             wordlist.cache_point = match wordlist.cache_size {
                 _ if (wordlist.cache_point + wordlist.cache_size / 2) >= wordlist.length => {
                     wordlist.length
                 }
-                _ => (wordlist.cache_point + wordlist.cache_size / 2),
+                _ => wordlist.cache_point + wordlist.cache_size / 2,
             };
             /*
               // Some debugging stats
@@ -490,16 +2629,252 @@ fn read_wordlist(
               */
         }
     }
-    Ok(stats)
+    Ok(())
+}
+/*}}}*/
+
+fn line_hash(pos: usize, line: usize, clear: &[u8]) -> u64 {
+    // Hash where a line is and what's in it, so a sum of them says which lines were wanted, not only how many /*{{{*/
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (pos, line, clear).hash(&mut hasher);
+    hasher.finish()
+}
+/*}}}*/
+
+struct Reference {
+    // A slow, plain way of matching lines, sharing nothing with the Matcher, to --verify the threads against /*{{{*/
+    values: Vec<Vec<u8>>,           // folded, to find anywhere in a folded line
+    lines: HashSet<Vec<u8>>,        // folded, for a folded line to equal
+    regexes: Vec<Regex>,            // each built on its own, without a set or prefilter
+    fold: CaseFold,
+    exact: bool,
+    invert: bool,
+}
+/*}}}*/
+
+fn reference_fold(value: &[u8], fold: CaseFold) -> Vec<u8> {
+    // Fold case with the standard library, rather than fold_case /*{{{*/
+    match (fold, std::str::from_utf8(value)) {
+        (CaseFold::Sensitive, _) => value.to_vec(),
+        // we treat a final sigma as any other
+        (CaseFold::Unicode, Ok(text)) => text.to_lowercase().replace('ς', "σ").into_bytes(),
+        (CaseFold::Unicode | CaseFold::Ascii, _) => value.to_ascii_lowercase(),
+    }
+}
+/*}}}*/
+
+fn build_reference(args: &Args, patterns: &[NamedRegex], fold: CaseFold) -> Result<Reference, SingrepError> {
+    // Build the reference matcher from the command line, reading any file of values for itself /*{{{*/
+    let (ignore_case, unicode) = (fold != CaseFold::Sensitive, fold != CaseFold::Ascii);
+    let mut regexes = Vec::new();
+    let mut needles = Vec::new();
+    if args.regex {
+        for (_, pattern) in patterns {
+            regexes.push(RegexBuilder::new(pattern).case_insensitive(ignore_case).unicode(unicode).build()?);
+        }
+    } else if let Some(path) = args.patterns_file.as_ref().or(args.intersect.as_ref()) {
+        // empty lines in a file of values are ignored
+        let contents = std::fs::read(path).map_err(io_error(path))?;
+        needles.extend(contents.split(|c| *c == 10_u8).filter(|l| !l.is_empty()).map(<[u8]>::to_vec));
+    } else {
+        needles.push(args.tofind.clone().unwrap_or_default().into_bytes());
+    }
+    let exact = args.exact || args.intersect.is_some();
+    let folded = needles.iter().map(|needle| reference_fold(needle, fold));
+    let (values, lines) = match exact {
+        true => (Vec::new(), folded.collect()),
+        false => (folded.collect(), HashSet::new()),
+    };
+    Ok(Reference {
+        values,
+        lines,
+        regexes,
+        fold,
+        exact,
+        invert: args.invert_match,
+    })
+}
+/*}}}*/
+
+fn reference_selected(reference: &Reference, clear: &[u8]) -> bool {
+    // Decide if a line is one we want, the slow way /*{{{*/
+    let found = if !reference.regexes.is_empty() {
+        reference.regexes.iter().any(|re| re.is_match(clear))
+    } else {
+        let clear = reference_fold(clear, reference.fold);
+        match reference.exact {
+            true => reference.lines.contains(&clear),
+            false => reference
+                .values
+                .iter()
+                .any(|value| value.is_empty() || clear.windows(value.len()).any(|window| window == value.as_slice())),
+        }
+    };
+    found != reference.invert
+}
+/*}}}*/
+
+fn verify_wordlist(reference: &Reference, path: &Path, origin: usize, totals: &mut Stats) -> Result<usize, SingrepError> {
+    // Search a wordlist again, a line at a time on this thread alone, tallying what the threads should have found /*{{{*/
+    if !std::fs::metadata(path).map_err(io_error(path))?.is_file() {
+        return Err(SingrepError::Invalid(format!(
+            "{}: can't be read a second time to verify it",
+            path.display()
+        )));
+    }
+    let mut file = File::open(path).map_err(io_error(path))?;
+    let mut magic = [0u8; 6];
+    let len = file.read(&mut magic).map_err(io_error(path))?;
+    file.seek(SeekFrom::Start(0)).map_err(io_error(path))?;
+    // none of the mapping, sharding, caching or prefiltering the threads rely on
+    let mut input: Box<dyn BufRead> = match compression(&magic[..len]) {
+        Some(kind) => Box::new(BufReader::new(decoder(kind, file).map_err(io_error(path))?)),
+        None => Box::new(BufReader::with_capacity(1_048_576, file)),
+    };
+    let mut line = Vec::new();
+    let mut number = 0;
+    let mut pos = origin;
+    loop {
+        line.clear();
+        let read = input.read_until(10, &mut line).map_err(io_error(path))?;
+        if read == 0 {
+            break;
+        }
+        number += 1;
+        let clear = line.strip_suffix(b"\n").unwrap_or(&line);
+        if !clear.is_empty() {
+            totals.hashed += 1;
+        }
+        if reference_selected(reference, clear) {
+            totals.cracked += 1;
+            totals.checksum = totals.checksum.wrapping_add(line_hash(pos, number, clear));
+        }
+        pos += read;
+    }
+    Ok(pos - origin)
+}
+/*}}}*/
+
+fn parse_args() -> Args {
+    // Parse the command line, with a patterns file every positional is a wordlist /*{{{*/
+    let mut args = Args::parse();
+    let needle_file =
+        args.patterns_file.is_some() || args.intersect.is_some() || !args.regexp.is_empty();
+    // --regexp is always a regex
+    args.regex |= !args.regexp.is_empty();
+    if args.format.is_some() && !args.regex {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--format needs a regex, from --regex or --regexp",
+            )
+            .exit();
+    }
+    if needle_file {
+        if let Some(wordlist) = args.tofind.take() {
+            args.wordlist.insert(0, PathBuf::from(wordlist));
+        }
+    }
+    if args.wordlist.is_empty() && args.files_from.is_none() {
+        let missing = match args.tofind {
+            None if !needle_file => "<TOFIND>\n  <WORDLIST>...",
+            _ => "<WORDLIST>...",
+        };
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                format!("the following required arguments were not provided:\n  {missing}"),
+            )
+            .exit();
+    }
+    args
+}
+/*}}}*/
+
+fn main() -> ExitCode {
+    // Exit like grep, 0 if anything matched, 1 if nothing did and 2 if something went wrong /*{{{*/
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("singrep: {e}");
+            ExitCode::from(2)
+        }
+    }
 }
 /*}}}*/
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn run() -> Result<ExitCode, SingrepError> {
     // Put it all together /*{{{*/
-    let args = Args::parse();
+    let mut args = parse_args();
+
+    let fold = match (args.ignore_case, args.unicode) {
+        (false, _) => CaseFold::Sensitive,
+        (true, false) => CaseFold::Ascii,
+        (true, true) => CaseFold::Unicode,
+    };
+    // Gather the regexes from the command line and any file of them
+    let mut patterns: Vec<NamedRegex> = Vec::new();
+    if args.regex {
+        patterns.extend(args.tofind.clone().map(|p| (None, p)));
+        patterns.extend(args.regexp.iter().map(|p| (None, p.clone())));
+        if let Some(path) = &args.patterns_file {
+            patterns.extend(read_regexes(path)?);
+        }
+    }
+    // the literal matcher is unused with regexes, but still needs something to find
+    let first_pattern = patterns.first().map(|(_, p)| p.clone().into_bytes());
+    let regexes = parse_regexes(&patterns, fold)?;
+    let reference = match args.verify {
+        true => Some(build_reference(&args, &patterns, fold)?),
+        false => None,
+    };
+    if let Some(template) = &args.format {
+        for re in &regexes.list {
+            check_format(template, re)?;
+        }
+    }
 
     // Put the input hashes (to be cracked) into the required forms
-    let tofind = parse_tofind(&args.tofind)?;
+    let tofind = match (&args.intersect, &args.patterns_file) {
+        // millions of lines go straight into a hash set, without a list of them first
+        (Some(path), _) => parse_lines(path, fold)?,
+        (None, Some(path)) if !args.regex => parse_tofind(read_needles(path)?, args.exact, fold, args.only_matching)?,
+        _ => parse_tofind(
+            vec![first_pattern.unwrap_or_else(|| args.tofind.clone().unwrap_or_default().into_bytes())],
+            args.exact,
+            fold,
+            args.only_matching,
+        )?,
+    };
+    let matcher = Matcher {
+        tofind: tofind.clone(),
+        regexes: regexes.list,
+        set: regexes.set,
+        names: regexes.names,
+        prefilter: match args.regex {
+            true => regexes.prefilter,
+            false => literal_prefilter(&tofind),
+        },
+        prefiltered: !args.regex && !args.exact && args.intersect.is_none(),
+        regex: args.regex,
+        exact: args.exact || args.intersect.is_some(),
+        invert: args.invert_match,
+        format: args.format.clone().map(String::into_bytes),
+    };
+
+    // Work out how many threads we're using, and which cores they're pinned to
+    let cores = match &args.pin {
+        Some(list) => parse_cores(list)?,
+        None => Vec::new(),
+    };
+    let threadnum = match (args.threads, cores.len()) {
+        (Some(threads), _) => threads,
+        (None, 0) => num_cpus::get(), // set the number of threads to the number of cores
+        (None, pinned) => pinned,
+    };
+    if args.auto_tune {
+        auto_tune(&mut args, threadnum);
+    }
 
     // Do some dd tests to find optimal block size for your HD
     // Here's an example, 1M is repeated to warm the file into cache
@@ -524,69 +2899,531 @@ fn main() -> Result<(), Box<dyn Error>> {
     let cache_size = args.cache;
 
     // size of wordlist chunk to send to thread
-    // if you're seeing too many waits, try optimising this by taking it via cmd
+    // if the threads are idle too long, try optimising this by taking it via cmd
     // line arg below and testing different sizes. 393k works well on a M1 Pro
     // MBP.
     //let chunk_size = 393_728;
     //let chunk_size = 5_248_000;
-    let chunk_size = args.shard;
+    // read_wordlist takes it from args.shard, which --auto-tune can change as it goes
 
-    // Build the wordlist (the clears to hash and check for a match)
-    let mut wordlist = initialise_wordlist(&args.wordlist, cache_size, block_size, args.verbose)?;
-    let workers = setup_workers(&tofind, &args);
+    // Gather the wordlists (the clears to hash and check for a match), and the files in any directories
+    let mut paths = args.wordlist.clone();
+    if let Some(list) = &args.files_from {
+        paths.extend(read_files_from(list)?);
+    }
+    if paths.iter().chain(&args.files_from).filter(|path| path.as_os_str() == "-").count() > 1 {
+        return Err(SingrepError::Invalid("standard input can only be read once".to_string()));
+    }
+    if args.verify && paths.iter().any(|path| path.as_os_str() == "-") {
+        return Err(SingrepError::Invalid("--verify can't search standard input, it can only be read once".to_string()));
+    }
+    // nor pipes and devices, we'd only find out after searching them, directories only give us files
+    for path in paths.iter().filter(|_| args.verify) {
+        if std::fs::metadata(path).is_ok_and(|meta| !meta.is_file() && !meta.is_dir()) {
+            return Err(SingrepError::Invalid(format!(
+                "--verify can't search {}, it can only be read once",
+                path.display()
+            )));
+        }
+    }
+    // lines are prefixed with the wordlist they're from if there could be more than one
+    let named = paths.len() > 1 || args.files_from.is_some() || paths.iter().any(|path| path.is_dir());
+    let mut wordlists = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        find_files(path, &mut wordlists, &mut errors);
+    }
+    // like grep, search what we can and report the rest
+    let mut failed = !errors.is_empty(); // whether any wordlist couldn't be searched
+    for e in errors {
+        eprintln!("singrep: {e}");
+    }
+    let names = matcher.names.clone();
+    let workers = setup_workers(matcher, &args, threadnum, &cores);
+    // the reader gets the core after the last thread's
+    let reader_pinned = pin_to(&cores, threadnum);
+    if args.verbose {
+        match cores.is_empty() {
+            true => write_verbose(format_args!("[+] Searching with {threadnum} threads"), &workers.stop)?,
+            false => {
+                // only the pins that took
+                let pinned: Vec<String> = (0..threadnum)
+                    .filter(|j| workers.pinned[*j])
+                    .map(|j| cores[j % cores.len()].to_string())
+                    .collect();
+                let threads = match pinned.len() {
+                    0 => ", none of them pinned".to_string(),
+                    n if n == threadnum => format!(" on cores {}", pinned.join(",")),
+                    n => format!(", {n} of them on cores {}", pinned.join(",")),
+                };
+                let reader = match reader_pinned {
+                    true => format!("reading on core {}", cores[threadnum % cores.len()]),
+                    false => "reading unpinned".to_string(),
+                };
+                write_verbose(format_args!("[+] Searching with {threadnum} threads{threads}, {reader}"), &workers.stop)?
+            }
+        }
+    }
     let start = Instant::now();
-    let mut stats = read_wordlist(&mut wordlist, chunk_size, &workers, args.first, block_size)?;
-    // All done reading the wordlist, now it's up to the threads to finish
-
-    // Make sure the workers have picked up all the chunks
-    loop {
-        if workers.tx.is_empty() {
+    let mut found = Found {
+        bits: vec![0; needle_count(&tofind).div_ceil(64)],
+        count: 0,
+        total: needle_count(&tofind),
+    };
+    let mut stats = Stats {
+        cracked: 0,
+        hashed: 0,
+        waited: Duration::ZERO,
+        blocked: Duration::ZERO,
+        read: 0,
+        hits: Vec::new(),
+        shard: args.shard,
+        checksum: 0,
+    };
+    let mut stopped = None;
+    // The wordlists are read one after another into the same threads, each
+    // with its own cache window
+    for path in &wordlists {
+        // the threads found everything we need in the wordlists before this one
+        if workers.stop.load(Ordering::Relaxed) || (args.first_each && found.count == found.total) {
             break;
         }
-        thread::sleep(std::time::Duration::from_millis(2_u64));
+        let label = match path.as_os_str() == "-" {
+            true => &b"(standard input)"[..],
+            false => path.as_os_str().as_bytes(),
+        };
+        let name: Option<Arc<[u8]>> = named.then(|| Arc::from(label));
+        let searched = match open_source(path, cache_size, block_size, threadnum, &cores, args.verbose, &workers.stop) {
+            Ok(Source::File(mut wordlist)) => read_wordlist(&mut wordlist, &workers, &args, &mut found, &mut stats, name),
+            Ok(Source::Stream(input)) => read_stream(input, &workers, &args, &mut found, &mut stats, name, path),
+            Err(e) => Err(e),
+        };
+        match searched {
+            Ok(()) => {}
+            // the threads are gone, they'll say why once they're joined
+            Err(e @ SingrepError::Thread(_)) => {
+                stopped = Some(e);
+                break;
+            }
+            Err(e) => {
+                eprintln!("singrep: {e}");
+                failed = true;
+            }
+        }
     }
-    // tell the threads to exit, as many times as there are threads
-    for _ in 0..workers.threadnum {
-        workers.tx.send(None)?;
+    if args.auto_tune {
+        eprintln!("[+] Auto-tuned shard size ended at {}", stats.shard);
     }
-    // wait for threads to exit
+    // All done reading the wordlist, now it's up to the threads to finish
+
+    // closing the channel tells the threads to exit once they've emptied it
+    drop(workers.tx);
+    // wait for threads to exit, the first to fail says why we stopped
     // Don't try put this in a function JoinHandle<()> doesn't implement Copy
+    let mut joined = Ok(());
     for thread in workers.threadhand {
-        thread.join().unwrap();
+        joined = joined.and(thread.join().unwrap_or(Err(SingrepError::Thread("a search thread crashed"))));
+    }
+    if let Some(writer) = workers.writer {
+        joined = joined.and(writer.join().unwrap_or(Err(SingrepError::Thread("the output thread crashed"))));
+    }
+    joined?;
+    if let Some(e) = stopped {
+        return Err(e);
     }
 
-    if args.verbose {
-        // get final numbers
-        while let Ok(recv_stats) = workers.rx2.try_recv() {
-            stats.cracked += recv_stats.cracked;
-            stats.hashed += recv_stats.hashed;
-            stats.waits += recv_stats.waits;
+    if let Some(path) = &args.left {
+        // merge the rest of what the threads found, and report what nobody did
+        while let Ok(ids) = workers.rx3.try_recv() {
+            merge_found(&mut found, &ids);
+        }
+        write_left(&tofind, &found.bits, path)?;
+    }
+
+    // get final numbers, each thread sends its totals as it exits
+    while let Ok(recv_stats) = workers.rx2.try_recv() {
+        stats.cracked += recv_stats.cracked;
+        stats.hashed += recv_stats.hashed;
+        stats.waited += recv_stats.waited;
+        stats.checksum = stats.checksum.wrapping_add(recv_stats.checksum);
+        stats.hits.resize(recv_stats.hits.len().max(stats.hits.len()), 0);
+        for (total, hits) in stats.hits.iter_mut().zip(recv_stats.hits) {
+            *total += hits;
+        }
+    }
+
+    // threads may have found more than we were limited to, but no more than that were printed
+    if let Some(limit) = match_limit(&args) {
+        stats.cracked = stats.cracked.min(limit);
+        for hits in &mut stats.hits {
+            *hits = (*hits).min(limit);
         }
+    }
+
+    if args.count {
+        if let Err(e) = writeln!(stdout(), "{}", stats.cracked) {
+            output_failed(e, &workers.stop)?;
+        }
+    }
+
+    if args.verbose {
 
         // calculate performance stats
         let elapsed = (start.elapsed().as_secs() as f64)
             + (f64::from(start.elapsed().subsec_nanos()) / 1_000_000_000.0);
         //safe usize->f64 conversion checks
         assert!(stats.hashed <= f64::MAX as usize);
-        assert!(stats.kbs <= f64::MAX as usize);
-        assert!(stats.waits <= f64::MAX as usize);
-        print!(
-            "[+] Stats:
+        assert!(stats.read <= f64::MAX as usize);
+        write_verbose(
+            format_args!(
+                "[+] Stats:
       Time: {:.2} s
       Lines Checked: {}, Found: {}, Find Speed: {:.2} kF/s
       Read: {} kB, Read Speed: {:.2} MB/s
-      Thread Waits: {} Wait Speed: {:.2} w/s\n",
-            elapsed,
-            stats.hashed,
-            stats.cracked,
-            (stats.hashed as f64 / elapsed) / 1024_f64,
-            stats.kbs,
-            (stats.kbs as f64 / elapsed) / 1024_f64,
-            stats.waits,
-            stats.waits as f64 / elapsed
+      Thread Idle: {:.2} s ({:.2}% of thread time), Reader Blocked: {:.2} s",
+                elapsed,
+                stats.hashed,
+                stats.cracked,
+                (stats.hashed as f64 / elapsed) / 1024_f64,
+                stats.read / 1024,
+                (stats.read as f64 / 1024_f64 / elapsed) / 1024_f64,
+                stats.waited.as_secs_f64(),
+                stats.waited.as_secs_f64() / (elapsed * workers.threadnum as f64) * 100_f64,
+                stats.blocked.as_secs_f64()
+            ),
+            &workers.stop,
+        )?;
+        // with more than one regex, how many lines each one matched
+        for (name, hits) in names.iter().zip(&stats.hits) {
+            write_verbose(format_args!("      Pattern {}: {}", String::from_utf8_lossy(name), hits), &workers.stop)?;
+        }
+    }
+
+    // a search cut short, by an error or by whatever's reading our output, has nothing to check
+    if let Some(reference) = reference.filter(|_| !failed && !workers.stop.load(Ordering::Relaxed)) {
+        let mut expected = Stats {
+            cracked: 0,
+            hashed: 0,
+            waited: Duration::ZERO,
+            blocked: Duration::ZERO,
+            read: 0,
+            hits: Vec::new(),
+            shard: 0,
+            checksum: 0,
+        };
+        for path in &wordlists {
+            expected.read += verify_wordlist(&reference, path, expected.read, &mut expected)?;
+        }
+        let threads = (stats.cracked, stats.hashed, stats.checksum);
+        let sequential = (expected.cracked, expected.hashed, expected.checksum);
+        if threads != sequential {
+            return Err(SingrepError::Mismatch(threads, sequential));
+        }
+        // to stderr, so it's never mistaken for what we found
+        eprintln!("[+] Verified {} lines found and {} lines checked against a sequential search", stats.cracked, stats.hashed);
+    }
+
+    Ok(match (failed, stats.cracked > 0) {
+        (true, _) => ExitCode::from(2),
+        (false, true) => ExitCode::SUCCESS,
+        (false, false) => ExitCode::from(1),
+    })
+}
+/*}}}*/
+
+#[cfg(test)]
+mod tests {
+    // Unit tests for the parts that are easy to get subtly wrong /*{{{*/
+    use super::*;
+
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        // A bgzf block as bgzip writes it, a gzip member with its size in a BC extra field
+        let mut deflate = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        deflate.write_all(data).unwrap();
+        let body = deflate.finish().unwrap();
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        let mut block = vec![0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0];
+        block.extend_from_slice(&((18 + body.len() + 8 - 1) as u16).to_le_bytes());
+        block.extend_from_slice(&body);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    fn bgzf(pieces: &[&[u8]]) -> (Vec<u8>, Vec<usize>) {
+        // A bgzf file of the pieces and the empty block it ends with, and where each block starts
+        let mut file = Vec::new();
+        let mut starts = Vec::new();
+        for piece in pieces.iter().chain([&&b""[..]]) {
+            starts.push(file.len());
+            file.extend(bgzf_block(piece));
+        }
+        (file, starts)
+    }
+
+    fn seekable_zstd(pieces: &[&[u8]]) -> (Vec<u8>, Vec<usize>) {
+        // A file in zstd's seekable format, a frame per piece then the seek table in a skippable frame
+        let mut file = Vec::new();
+        let mut starts = Vec::new();
+        let mut table = Vec::new();
+        for piece in pieces {
+            starts.push(file.len());
+            let frame = zstd::bulk::compress(piece, 3).unwrap();
+            table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(piece.len() as u32).to_le_bytes());
+            file.extend(frame);
+        }
+        table.extend_from_slice(&(pieces.len() as u32).to_le_bytes());
+        table.push(0); // no checksums
+        table.extend_from_slice(&0x8f92_eab1_u32.to_le_bytes());
+        file.extend_from_slice(&0x184d_2a5e_u32.to_le_bytes());
+        file.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        file.extend(table);
+        (file, starts)
+    }
+
+    fn inflate(kind: Compression, data: &[u8]) -> Vec<u8> {
+        // What the decoder makes of it, to check the fixtures are the real thing
+        let mut out = Vec::new();
+        decoder(kind, data).unwrap().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn inflate_groups(kind: Compression, data: &[u8], frames: &[usize]) -> Vec<u8> {
+        // Decompress each group of frames on its own, the way the threads do
+        let mut out = Vec::new();
+        for (start, end) in group_frames(frames, data.len()) {
+            out.extend(inflate(kind, &data[start..end]));
+        }
+        out
+    }
+
+    #[test]
+    fn bgzf_blocks_found() {
+        let (file, starts) = bgzf(&[b"one\ntwo\n", b"three\n", b"four\nfive"]);
+        assert_eq!(inflate(Compression::Gzip, &file), b"one\ntwo\nthree\nfour\nfive");
+        assert_eq!(bgzf_blocks(&file), Some(starts.clone()));
+        assert_eq!(inflate_groups(Compression::Gzip, &file, &starts), b"one\ntwo\nthree\nfour\nfive");
+    }
+
+    #[test]
+    fn bgzf_blocks_other_extra_fields() {
+        // the BC field needn't be the only one, or the first
+        let mut block = bgzf_block(b"line\n");
+        block[10] = 10; // xlen
+        block.splice(12..12, [b'X', b'Y', 0, 0]);
+        block[20] += 4; // the block size
+        assert_eq!(inflate(Compression::Gzip, &block), b"line\n");
+        assert_eq!(bgzf_blocks(&block), Some(vec![0]));
+    }
+
+    #[test]
+    fn bgzf_blocks_rejected() {
+        // plain gzip has no block sizes to go on
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(b"one\ntwo\n").unwrap();
+        assert_eq!(bgzf_blocks(&gzip.finish().unwrap()), None);
+        // nor does a BC field of the wrong length
+        let mut block = bgzf_block(b"line\n");
+        block[14] = 3;
+        assert_eq!(bgzf_blocks(&block), None);
+        // and a block size running past the end, or short of it, isn't bgzf
+        let mut block = bgzf_block(b"line\n");
+        block[16] += 1;
+        assert_eq!(bgzf_blocks(&block), None);
+        let mut block = bgzf_block(b"line\n");
+        block[16] -= 1;
+        assert_eq!(bgzf_blocks(&block), None);
+    }
+
+    #[test]
+    fn bgzf_blocks_truncated() {
+        let (file, starts) = bgzf(&[b"one\ntwo\n", b"three\n"]);
+        for cut in 1..file.len() {
+            let expected = starts.contains(&cut).then(|| starts.iter().copied().filter(|&s| s < cut).collect());
+            assert_eq!(bgzf_blocks(&file[..cut]), expected, "cut at {cut}");
+        }
+        assert_eq!(bgzf_blocks(b""), Some(vec![]));
+    }
+
+    #[test]
+    fn zstd_frames_seekable() {
+        let (file, starts) = seekable_zstd(&[b"one\ntwo\n", b"three\n", b"four\nfive"]);
+        assert_eq!(inflate(Compression::Zstd, &file), b"one\ntwo\nthree\nfour\nfive");
+        assert_eq!(zstd_frames(&file), Some(starts.clone()));
+        assert_eq!(inflate_groups(Compression::Zstd, &file, &starts), b"one\ntwo\nthree\nfour\nfive");
+    }
+
+    #[test]
+    fn zstd_frames_leading_skippable() {
+        // the first frame needn't be at the start, the first group still is
+        let (seekable, _) = seekable_zstd(&[b"one\n", b"two\n"]);
+        let mut file = vec![0x50, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3];
+        file.extend(&seekable);
+        let frames = zstd_frames(&file).unwrap();
+        assert_eq!(frames[0], 11);
+        assert_eq!(inflate(Compression::Zstd, &file), b"one\ntwo\n");
+        assert_eq!(inflate_groups(Compression::Zstd, &file, &frames), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn zstd_frames_block_types() {
+        // a single segment frame with a one byte content size, a raw block then an RLE block of 5 x's
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd, 0x20, 9];
+        frame.extend_from_slice(&[4 << 3, 0, 0]); // raw, 4 bytes
+        frame.extend_from_slice(b"abc\n");
+        frame.extend_from_slice(&[5 << 3 | 1 << 1 | 1, 0, 0]); // RLE, 5 bytes, last
+        frame.push(b'x');
+        let mut two = frame.clone();
+        two.extend(&frame);
+        assert_eq!(inflate(Compression::Zstd, &two), b"abc\nxxxxxabc\nxxxxx");
+        assert_eq!(zstd_frames(&two), Some(vec![0, frame.len()]));
+        // block type 3 is reserved, so this isn't zstd we understand
+        let mut reserved = frame.clone();
+        reserved[6] |= 3 << 1;
+        assert_eq!(zstd_frames(&reserved), None);
+    }
+
+    #[test]
+    fn zstd_frames_checksum() {
+        let mut encoder = zstd::stream::Encoder::new(Vec::new(), 3).unwrap();
+        encoder.include_checksum(true).unwrap();
+        encoder.write_all(b"one\ntwo\n").unwrap();
+        let frame = encoder.finish().unwrap();
+        let mut two = frame.clone();
+        two.extend(&frame);
+        assert_eq!(inflate(Compression::Zstd, &two), b"one\ntwo\none\ntwo\n");
+        assert_eq!(zstd_frames(&two), Some(vec![0, frame.len()]));
+    }
+
+    #[test]
+    fn zstd_frames_truncated() {
+        let (file, starts) = seekable_zstd(&[b"one\ntwo\n", b"three\n"]);
+        let table = file.len() - (8 + 2 * 8 + 9);
+        for cut in 1..file.len() {
+            let expected = (starts.contains(&cut) || cut == table)
+                .then(|| starts.iter().copied().filter(|&s| s < cut).collect());
+            assert_eq!(zstd_frames(&file[..cut]), expected, "cut at {cut}");
+        }
+        // and garbage after the frames isn't either
+        let mut file = file;
+        file.push(0);
+        assert_eq!(zstd_frames(&file), None);
+    }
+
+    fn literals(pattern: &str) -> Option<Vec<Vec<u8>>> {
+        // The literals a regex needs, in order so they can be compared
+        let hir = ParserBuilder::new().utf8(false).build().parse(pattern).unwrap();
+        required_literals(&hir).map(|mut literals| {
+            literals.sort_unstable();
+            literals
+        })
+    }
+
+    fn bytes(literals: &[&str]) -> Option<Vec<Vec<u8>>> {
+        Some(literals.iter().map(|l| l.as_bytes().to_vec()).collect())
+    }
+
+    #[test]
+    fn literals_concat() {
+        assert_eq!(literals("foo"), bytes(&["foo"]));
+        // the part with the longest literal is the one to look for
+        assert_eq!(literals("foo[0-9]+barbaz"), bytes(&["barbaz"]));
+        assert_eq!(literals("(foo)[0-9]*(ba)"), bytes(&["foo"]));
+        // fewer literals of the same length are better
+        assert_eq!(literals("ab(cd|ef)[0-9]"), bytes(&["ab"]));
+        assert_eq!(literals("[0-9]+"), None);
+        assert_eq!(literals("a.*bc"), bytes(&["bc"]));
+    }
+
+    #[test]
+    fn literals_alternation() {
+        assert_eq!(literals("foo|barbaz"), bytes(&["barbaz", "foo"]));
+        assert_eq!(literals("(foo|bar)[0-9]"), bytes(&["bar", "foo"]));
+        // an empty branch matches without any literal
+        assert_eq!(literals("foo|"), None);
+        assert_eq!(literals("(|foo)"), None);
+        assert_eq!(literals("(foo|)bar"), bytes(&["bar"]));
+        // as does a branch with no literal of its own
+        assert_eq!(literals("foo|[0-9]+"), None);
+    }
+
+    #[test]
+    fn literals_repetition() {
+        assert_eq!(literals("(foo){0,}"), None);
+        assert_eq!(literals("(foo)*"), None);
+        assert_eq!(literals("(foo)?"), None);
+        assert_eq!(literals("(foo){0,3}"), None);
+        assert_eq!(literals("(foo){1,}"), bytes(&["foo"]));
+        assert_eq!(literals("(foo)+"), bytes(&["foo"]));
+        assert_eq!(literals("(foo){2,5}"), bytes(&["foo"]));
+        assert_eq!(literals("x*foo"), bytes(&["foo"]));
+    }
+
+    #[test]
+    fn literals_case_insensitive() {
+        // folded letters are classes, not literals
+        assert_eq!(literals("(?i)foo"), None);
+        assert_eq!(literals("foo(?i:bar)"), bytes(&["foo"]));
+        assert_eq!(literals("(?i:foo)barbaz"), bytes(&["barbaz"]));
+        assert_eq!(literals("(?i)foo|bar"), None);
+    }
+
+    #[test]
+    fn literals_look_around() {
+        assert_eq!(literals(r"\bfoo\b"), bytes(&["foo"]));
+        assert_eq!(literals("^foo$"), bytes(&["foo"]));
+        assert_eq!(literals(r"(?m)^foo\B"), bytes(&["foo"]));
+        assert_eq!(literals("^$"), None);
+        assert_eq!(literals(r"\b"), None);
+    }
+
+    #[test]
+    fn literals_newline() {
+        // nothing spanning a newline can be in a line, so it's no use looking for it
+        assert_eq!(literals(r"foo\nbar"), None);
+        assert_eq!(literals("\n"), None);
+        assert_eq!(literals(r"foo|\n"), None);
+        assert_eq!(literals(r"foo[0-9]\nbar"), bytes(&["foo"]));
+    }
+
+    #[test]
+    fn prefilter_bytes() {
+        // without Unicode, as with ASCII case folding, \xFF is the byte, not ÿ
+        let patterns = vec![(None, r"a\xFFb".to_string())];
+        let prefilter = build_prefilter(&patterns, CaseFold::Ascii).unwrap();
+        assert_eq!(next_candidate(&prefilter, b"xyz\nA\xffB\n", 0), Some(4));
+        let prefilter = build_prefilter(&patterns, CaseFold::Sensitive).unwrap();
+        assert_eq!(next_candidate(&prefilter, "xyz\naÿb\n".as_bytes(), 0), Some(4));
+        assert_eq!(build_prefilter(&patterns, CaseFold::Unicode).map(|_| ()), None);
+    }
+
+    #[test]
+    fn frames_grouped() {
+        assert_eq!(group_frames(&[0], 10), vec![(0, 10)]);
+        assert_eq!(group_frames(&[0, 10, 20], 30), vec![(0, 30)]);
+        // groups start on frames, and close once they're at least INFLATE_GROUP
+        let mb = INFLATE_GROUP;
+        assert_eq!(
+            group_frames(&[0, 2 * mb, 2 * mb + mb / 2, 4 * mb], 5 * mb),
+            vec![(0, 2 * mb), (2 * mb, 4 * mb), (4 * mb, 5 * mb)]
         );
+        // a leading skippable frame stays in the first group
+        assert_eq!(group_frames(&[11, mb + 11], 2 * mb), vec![(0, mb + 11), (mb + 11, 2 * mb)]);
     }
 
-    Ok(())
+    #[test]
+    fn format_names() {
+        let re = Regex::new(r"(?P<user>\w+):(\w+)").unwrap();
+        assert!(check_format("$user $2 ${user}s $$nope $", &re).is_ok());
+        // names stop at the first character Captures::expand wouldn't take
+        assert!(check_format("$userç $user-x", &re).is_ok());
+        assert!(check_format("$usr", &re).is_err());
+        assert!(check_format("${usr}", &re).is_err());
+        assert!(check_format("$3", &re).is_err());
+        // an unclosed brace is printed as it is
+        assert!(check_format("${usr", &re).is_ok());
+    }
 }
 /*}}}*/
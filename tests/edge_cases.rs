@@ -0,0 +1,110 @@
+// Run singrep over small wordlists for the edge cases that are easy to get wrong,
+// checking what it prints against grep's behaviour, and that --verify agrees
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+struct Wordlist(PathBuf);
+
+impl Drop for Wordlist {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn wordlist(name: &str, contents: &[u8]) -> Wordlist {
+    // A wordlist of its own for each test, as they run at the same time
+    let path = std::env::temp_dir().join(format!("singrep-{}-{name}", std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    Wordlist(path)
+}
+
+fn singrep(args: &[&str], wordlist: &Wordlist) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_singrep"))
+        .args(["--verify", "--ordered", "--threads", "3"])
+        .args(args)
+        .arg(&wordlist.0)
+        .output()
+        .unwrap()
+}
+
+fn assert_found(args: &[&str], wordlist: &Wordlist, expected: &str) {
+    // what's printed, the exit status, and that the threads agree with --verify
+    let output = singrep(args, wordlist);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected, "{args:?}");
+    let status = if expected.is_empty() { 1 } else { 0 };
+    assert_eq!(output.status.code(), Some(status), "{args:?}");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("[+] Verified"), "{args:?}");
+}
+
+#[test]
+fn empty_file() {
+    let empty = wordlist("empty", b"");
+    assert_found(&["foo"], &empty, "");
+    // there are no lines, empty or not
+    assert_found(&[""], &empty, "");
+    assert_found(&["-e", ""], &empty, "");
+    assert_found(&["--invert-match", "foo"], &empty, "");
+    assert_found(&["-r", "^$"], &empty, "");
+}
+
+#[test]
+fn empty_pattern() {
+    let blanks = wordlist("blanks", b"a\n\nb\n");
+    // every line has nothing in it
+    assert_found(&[""], &blanks, "a\n\nb\n");
+    assert_found(&["-n", ""], &blanks, "1:a\n2:\n3:b\n");
+    assert_found(&["--invert-match", ""], &blanks, "");
+    // but only empty lines are nothing but
+    assert_found(&["-e", ""], &blanks, "\n");
+    assert_found(&["-n", "-e", ""], &blanks, "2:\n");
+    assert_found(&["--invert-match", "-e", ""], &blanks, "a\nb\n");
+}
+
+#[test]
+fn unterminated_last_line() {
+    let unterminated = wordlist("unterminated", b"abc\nxbc");
+    assert_found(&["x"], &unterminated, "xbc\n");
+    // skipping from the last match to the end still counts the last line as checked
+    assert_found(&["ab"], &unterminated, "abc\n");
+    assert_found(&["-n", "bc"], &unterminated, "1:abc\n2:xbc\n");
+    assert_found(&["-e", "xbc"], &unterminated, "xbc\n");
+    assert_found(&["-r", "c$"], &unterminated, "abc\nxbc\n");
+    assert_found(&["-n", ""], &unterminated, "1:abc\n2:xbc\n");
+    assert_found(&["-p", "xbc"], &unterminated, "4:xbc\n");
+    assert_found(&["-B", "1", "x"], &unterminated, "abc\nxbc\n");
+}
+
+#[test]
+fn shard_ends_on_newline() {
+    // a 4 byte shard ends exactly on the first newline, and 1 byte shards on every one
+    let lines = wordlist("lines", b"aaa\nbbb\nccc\n");
+    for shard in ["1", "4", "8"] {
+        assert_found(&["-s", shard, "-n", "a"], &lines, "1:aaa\n");
+        assert_found(&["-s", shard, "-n", "b"], &lines, "2:bbb\n");
+        assert_found(&["-s", shard, "-n", "-e", "ccc"], &lines, "3:ccc\n");
+        assert_found(&["-s", shard, "-p", "c"], &lines, "8:ccc\n");
+        assert_found(&["-s", shard, "-n", "-A", "1", "a"], &lines, "1:aaa\n2-bbb\n");
+        assert_found(&["-s", shard, "-n", "-B", "1", "c"], &lines, "2-bbb\n3:ccc\n");
+        assert_found(&["-s", shard, "--invert-match", "b"], &lines, "aaa\nccc\n");
+        assert_found(&["-s", shard, "-e", "bb"], &lines, "");
+    }
+}
+
+#[test]
+fn stdin_shard_ends_on_newline() {
+    // standard input can't be verified, it can only be read once
+    for shard in ["1", "4", "8"] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_singrep"))
+            .args(["--ordered", "-s", shard, "-n", "-C", "1", "b", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"aaa\nbbb\nccc\nddd").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1-aaa\n2:bbb\n3-ccc\n");
+        assert_eq!(output.status.code(), Some(0));
+    }
+}